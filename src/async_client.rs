@@ -0,0 +1,291 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{Sink, SinkExt, Stream, ready};
+use rustls::pki_types::ServerName;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::Framed;
+
+use crate::{
+    client::{
+        ClientConfig, ClientState, Event, Inbox, SendError, handshake, load_identity, outgoing,
+    },
+    e2e::{self, Identity},
+    parser::{IdentityKey, Message, PeerId, SYSTEM_PEER, SystemMessage},
+    shared::MessageCodec,
+    tls,
+};
+
+// A bare socket or TLS over it.
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+// The client for tokio apps: a Stream of the same events the blocking Client hands out, and a
+// Sink of messages. A message to a peer is encrypted on the way out and tracked under its id,
+// or a new one if it has none, so set the id to match it with later Status events. Requests
+// to the server are SystemMessages sent to SYSTEM_PEER, see `system`.
+//
+// There is no reconnecting: the stream ends with the connection. Heartbeats are answered
+// while the stream is polled, so keep polling it.
+pub struct AsyncClient {
+    framed: Framed<Box<dyn Transport>, MessageCodec>,
+    state: Arc<Mutex<ClientState>>,
+    identity: Arc<Identity>,
+    inbox: Inbox,
+}
+
+impl AsyncClient {
+    // Connects to `addr` with the default settings.
+    pub async fn connect(addr: &str, username: &str, password: &str) -> io::Result<Self> {
+        let config = ClientConfig {
+            server_addr: addr.to_string(),
+            ..ClientConfig::default()
+        };
+        Self::connect_with(config, username, password).await
+    }
+
+    // The heartbeat settings are not used; the server's pings are answered all the same.
+    pub async fn connect_with(
+        config: ClientConfig,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Self> {
+        let ClientConfig {
+            server_addr,
+            tls,
+            read_receipts,
+            identity,
+            ..
+        } = config;
+        let identity = Arc::new(load_identity(identity)?);
+        let sock = TcpStream::connect(&server_addr).await?;
+        let transport: Box<dyn Transport> = match tls {
+            Some(tls) => {
+                let config = tls::client_config(&tls)?;
+                let name = ServerName::try_from(tls.server_name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Box::new(TlsConnector::from(config).connect(name, sock).await?)
+            }
+            None => Box::new(sock),
+        };
+        let mut framed = Framed::new(transport, MessageCodec::default());
+        for msg in handshake(&identity, username, password) {
+            framed.feed(system(msg)).await?;
+        }
+        framed.flush().await?;
+        log::info!("Connected to {}", server_addr);
+
+        let state = Arc::new(Mutex::new(ClientState::new()));
+        let inbox = Inbox::new(state.clone(), identity.clone(), read_receipts);
+        Ok(AsyncClient {
+            framed,
+            state,
+            identity,
+            inbox,
+        })
+    }
+
+    // Everyone we know of with their name, which is empty for rooms.
+    pub fn peers(&self) -> Vec<(PeerId, String)> {
+        self.state.lock().unwrap().peer_list()
+    }
+
+    pub fn key(&self, peer: PeerId) -> Option<IdentityKey> {
+        self.state.lock().unwrap().key(peer)
+    }
+
+    // Our own key's fingerprint, for comparing with what peers see.
+    pub fn fingerprint(&self) -> String {
+        e2e::fingerprint(&self.identity.public_key())
+    }
+
+    // Tells `peer` that the direct messages from them that arrived so far have been read, see
+    // Client::mark_read.
+    pub async fn mark_read(&mut self, peer: PeerId) -> Result<(), SendError> {
+        let ids = self.state.lock().unwrap().take_unread(peer);
+        for id in ids {
+            self.framed
+                .feed(system(SystemMessage::Read { peer, id }))
+                .await
+                .map_err(SendError::Io)?;
+        }
+        self.framed.flush().await.map_err(SendError::Io)
+    }
+
+    // Writes out the replies the inbox has collected, as far as the sink has room for them.
+    fn poll_replies(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.inbox.replies.is_empty() {
+            ready!(Pin::new(&mut self.framed).poll_ready(cx))?;
+            let reply = self.inbox.replies.remove(0);
+            Pin::new(&mut self.framed).start_send(system(reply))?;
+        }
+        Pin::new(&mut self.framed).poll_flush(cx)
+    }
+}
+
+// A request for the server, to send through the sink.
+pub fn system(msg: SystemMessage) -> Message {
+    Message::new(SYSTEM_PEER, msg.encode(), false)
+}
+
+impl Stream for AsyncClient {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Event>> {
+        let this = self.get_mut();
+        loop {
+            // Still waiting to go out is fine; the events don't depend on it.
+            if let Poll::Ready(Err(e)) = this.poll_replies(cx) {
+                log::warn!("Connection lost: {}", e);
+                return Poll::Ready(None);
+            }
+            if let Some(event) = this.inbox.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match ready!(Pin::new(&mut this.framed).poll_next(cx)) {
+                Some(Ok(msg)) => this.inbox.handle(msg),
+                Some(Err(e)) => {
+                    log::warn!("Connection lost: {}", e);
+                    return Poll::Ready(None);
+                }
+                None => {
+                    log::info!("Connection closed by server");
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
+
+impl Sink<Message> for AsyncClient {
+    type Error = SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError>> {
+        Pin::new(&mut self.get_mut().framed)
+            .poll_ready(cx)
+            .map_err(SendError::Io)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), SendError> {
+        let this = self.get_mut();
+        let mut state = this.state.lock().unwrap();
+        let msg = outgoing(&mut state, &this.identity, msg.peer, &msg.content, msg.id)?;
+        drop(state);
+        Pin::new(&mut this.framed)
+            .start_send(msg)
+            .map_err(SendError::Io)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError>> {
+        Pin::new(&mut self.get_mut().framed)
+            .poll_flush(cx)
+            .map_err(SendError::Io)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError>> {
+        Pin::new(&mut self.get_mut().framed)
+            .poll_close(cx)
+            .map_err(SendError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Status,
+        server::{Server, ServerConfig},
+    };
+    use futures::StreamExt;
+    use std::{thread, time::Duration};
+
+    // Skips events until one matches.
+    async fn wait_for(client: &mut AsyncClient, pred: impl Fn(&Event) -> bool) -> Event {
+        let matching = async {
+            while let Some(event) = client.next().await {
+                if pred(&event) {
+                    return event;
+                }
+            }
+            panic!("connection ended");
+        };
+        tokio::time::timeout(Duration::from_secs(5), matching)
+            .await
+            .expect("no matching event arrived")
+    }
+
+    #[tokio::test]
+    async fn test_stream_and_sink() {
+        let server = Server::bind(ServerConfig {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            ..ServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let mut alice = AsyncClient::connect(&addr, "alice", "").await.unwrap();
+        wait_for(&mut alice, |e| matches!(e, Event::Peers(_))).await;
+        let mut bob = AsyncClient::connect(&addr, "bob", "").await.unwrap();
+        wait_for(&mut alice, |e| {
+            matches!(e, Event::System(SystemMessage::PeerKey { peer: 2, .. }))
+        })
+        .await;
+        wait_for(&mut bob, |e| {
+            matches!(e, Event::System(SystemMessage::PeerKey { peer: 1, .. }))
+        })
+        .await;
+        assert_eq!(
+            alice.peers(),
+            vec![(1, "alice".to_string()), (2, "bob".to_string())]
+        );
+
+        let mut msg = Message::new(2, b"hello bob".to_vec(), false);
+        msg.id = Some(5);
+        alice.send(msg).await.unwrap();
+        let Event::Message(received) = wait_for(&mut bob, |e| matches!(e, Event::Message(_))).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(received.content, b"hello bob");
+        assert_eq!(received.id, Some(5));
+        bob.mark_read(1).await.unwrap();
+        wait_for(&mut alice, |e| {
+            *e == Event::Status {
+                peer: 2,
+                id: 5,
+                status: Status::Read,
+            }
+        })
+        .await;
+
+        bob.send(system(SystemMessage::CreateRoom {
+            name: "general".to_string(),
+        }))
+        .await
+        .unwrap();
+        wait_for(&mut bob, |e| {
+            matches!(e, Event::System(SystemMessage::RoomCreated { .. }))
+        })
+        .await;
+        assert!(matches!(
+            alice.send(Message::new(9, vec![], false)).await,
+            Err(SendError::NoKey(9))
+        ));
+
+        alice.close().await.unwrap();
+        bob.close().await.unwrap();
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use crate::shared::{from_hex, to_hex};
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+// PBKDF2-HMAC-SHA256 rounds for new entries, a few tens of milliseconds per login since
+// logins are checked on the event loop. Each entry keeps its own count, so raising this only
+// affects users added afterwards.
+pub const DEFAULT_ROUNDS: u32 = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    UnknownUser,
+    BadPassword,
+}
+
+// Decides who may log in. Called on the server's event loop, so it must not block for long.
+pub trait Authenticator: Send {
+    fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError>;
+
+    // Whether logging in proves the name belongs to the user. Only then is the name bound to
+    // an id, along with the queued messages and history that come with it.
+    fn proves_identity(&self) -> bool {
+        true
+    }
+}
+
+// Lets anyone in under any name. Since that proves nothing, each session gets an id of its
+// own and nothing left behind by an earlier session under the same name.
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _username: &str, _password: &str) -> Result<(), AuthError> {
+        Ok(())
+    }
+
+    fn proves_identity(&self) -> bool {
+        false
+    }
+}
+
+struct Credential {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+// Users file with one "username:rounds:salt:hash" line per user, salt and hash in hex,
+// where hash = PBKDF2-HMAC-SHA256(password, salt, rounds). Blank lines and lines starting
+// with '#' are skipped.
+pub struct FileAuthenticator {
+    users: HashMap<String, Credential>,
+    // Checked against for names that aren't in the file, so they take as long to turn away
+    // as a wrong password does.
+    dummy: Credential,
+}
+
+impl FileAuthenticator {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed users file entry on line {}", number + 1),
+                )
+            };
+            let fields = line.split(':').collect::<Vec<_>>();
+            let [username, rounds, salt, hash] = fields[..] else {
+                // Entries from before the switch to PBKDF2 had only a salt and a hash
+                if fields.len() == 3 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Outdated users file entry on line {}, add the user again",
+                            number + 1
+                        ),
+                    ));
+                }
+                return Err(invalid());
+            };
+            let rounds = rounds
+                .parse()
+                .ok()
+                .filter(|rounds| *rounds > 0)
+                .ok_or_else(invalid)?;
+            let salt = from_hex(salt).ok_or_else(invalid)?;
+            let hash = from_hex(hash).ok_or_else(invalid)?;
+            let credential = Credential { rounds, salt, hash };
+            users.insert(username.to_string(), credential);
+        }
+        let dummy = Credential {
+            rounds: users
+                .values()
+                .map(|credential| credential.rounds)
+                .max()
+                .unwrap_or(DEFAULT_ROUNDS),
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; HASH_LEN],
+        };
+        Ok(FileAuthenticator { users, dummy })
+    }
+
+    // Builds a users file line for `username` with a fresh random salt.
+    pub fn entry(username: &str, password: &str) -> io::Result<String> {
+        Self::entry_with_rounds(username, password, DEFAULT_ROUNDS)
+    }
+
+    pub fn entry_with_rounds(username: &str, password: &str, rounds: u32) -> io::Result<String> {
+        let mut salt = [0; SALT_LEN];
+        getrandom::fill(&mut salt)?;
+        let hash = hash_password(password, &salt, rounds);
+        Ok(format!(
+            "{}:{}:{}:{}",
+            username,
+            rounds,
+            to_hex(&salt),
+            to_hex(&hash)
+        ))
+    }
+}
+
+impl Authenticator for FileAuthenticator {
+    fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let known = self.users.get(username);
+        let credential = known.unwrap_or(&self.dummy);
+        let hash = hash_password(password, &credential.salt, credential.rounds);
+        // Compare every byte so the time taken doesn't hint at how much matched.
+        let diff = hash
+            .iter()
+            .zip(&credential.hash)
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if known.is_none() {
+            return Err(AuthError::UnknownUser);
+        }
+        if diff != 0 || hash.len() != credential.hash.len() {
+            return Err(AuthError::BadPassword);
+        }
+        Ok(())
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8], rounds: u32) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_authenticator() {
+        let contents = format!(
+            "# users\n{}\n\n{}\n",
+            FileAuthenticator::entry_with_rounds("alice", "wonderland", 10).unwrap(),
+            FileAuthenticator::entry_with_rounds("bob", "p:a:s:s", 1).unwrap()
+        );
+        let auth = FileAuthenticator::parse(&contents).unwrap();
+        assert_eq!(auth.authenticate("alice", "wonderland"), Ok(()));
+        assert_eq!(auth.authenticate("bob", "p:a:s:s"), Ok(()));
+        assert_eq!(
+            auth.authenticate("alice", "p:a:s:s"),
+            Err(AuthError::BadPassword)
+        );
+        assert_eq!(auth.authenticate("carol", ""), Err(AuthError::UnknownUser));
+        // Unknown names cost as much as the dearest entry
+        assert_eq!(auth.dummy.rounds, 10);
+    }
+
+    #[test]
+    fn test_salts_differ_per_entry() {
+        let a = FileAuthenticator::entry_with_rounds("alice", "same", 1).unwrap();
+        let b = FileAuthenticator::entry_with_rounds("alice", "same", 1).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_rejects_malformed_users_file() {
+        assert!(FileAuthenticator::parse("alice:1:00").is_err());
+        assert!(FileAuthenticator::parse("alice:1:zz:00").is_err());
+        assert!(FileAuthenticator::parse("alice:1:0:00").is_err());
+        assert!(FileAuthenticator::parse("alice:0:00:00").is_err());
+        assert!(FileAuthenticator::parse("alice:x:00:00").is_err());
+        assert!(FileAuthenticator::parse("alice:1:00:00:extra").is_err());
+        // The old single-sha256 format
+        assert!(FileAuthenticator::parse("alice:00:00").is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Range,
+};
+
+use md_redis::{
+    Client, ConnectionState, Event, Status,
+    client::parse_command,
+    parser::{BROADCAST_PEER, PeerId, SYSTEM_PEER, SystemMessage, is_room},
+};
+
+// The line being typed, with a cursor counted in chars.
+#[derive(Default)]
+pub struct LineEditor {
+    text: String,
+    cursor: usize,
+}
+
+impl LineEditor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // The text before the cursor, for measuring where to draw it.
+    pub fn before_cursor(&self) -> &str {
+        &self.text[..self.byte_offset(self.cursor)]
+    }
+
+    fn byte_offset(&self, chars: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(chars)
+            .map_or(self.text.len(), |(i, _)| i)
+    }
+
+    pub fn insert(&mut self, c: char) {
+        let at = self.byte_offset(self.cursor);
+        self.text.insert(at, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.delete();
+        }
+    }
+
+    pub fn delete(&mut self) {
+        let at = self.byte_offset(self.cursor);
+        if at < self.text.len() {
+            self.text.remove(at);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.chars().count());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+
+    // Empties the editor and returns what was in it.
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+}
+
+// Everything a chat window shows apart from the drawing itself: who is there, which
+// conversation is open, what hasn't been read in the others and the line being typed.
+pub struct ChatView {
+    pub connection: ConnectionState,
+    pub selected: Option<PeerId>,
+    pub input: LineEditor,
+    // How many lines the open conversation is scrolled back from its end.
+    pub scroll: usize,
+    // The last thing that went wrong, or that the server said, for the status line.
+    pub notice: Option<String>,
+    me: PeerId,
+    // Everyone seen since we started, by id; those who left are kept to write to offline.
+    peers: BTreeMap<PeerId, String>,
+    online: HashSet<PeerId>,
+    rooms: BTreeSet<PeerId>,
+    room_names: HashMap<PeerId, String>,
+    unread: HashMap<PeerId, usize>,
+    // The latest news about messages we sent; the client forgets them once read or failed.
+    statuses: HashMap<u64, Status>,
+}
+
+impl ChatView {
+    pub fn new() -> Self {
+        ChatView {
+            connection: ConnectionState::Connecting,
+            selected: None,
+            input: LineEditor::default(),
+            scroll: 0,
+            notice: None,
+            me: SYSTEM_PEER,
+            peers: BTreeMap::new(),
+            online: HashSet::new(),
+            rooms: BTreeSet::new(),
+            room_names: HashMap::new(),
+            unread: HashMap::new(),
+            statuses: HashMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Connection(state) => self.connection = *state,
+            Event::Peers(peers) => {
+                let peers = peers.iter().filter(|(peer, _)| is_person(*peer));
+                let was_online = self.selected.filter(|peer| self.online.contains(peer));
+                self.online = peers.clone().map(|(peer, _)| *peer).collect();
+                self.peers.extend(peers.cloned());
+                if let Some(selected) = was_online
+                    && !self.online.contains(&selected)
+                {
+                    self.notice = Some(format!("{} went offline", self.name(selected)));
+                }
+            }
+            Event::Message(msg) => {
+                if is_room(msg.peer) {
+                    self.rooms.insert(msg.peer);
+                }
+                if self.selected != Some(msg.peer) {
+                    *self.unread.entry(msg.peer).or_default() += 1;
+                } else if self.scroll > 0 {
+                    // Keep the same lines in view as the new one is added below them
+                    self.scroll += 1;
+                }
+            }
+            Event::Status { peer, id, status } => {
+                if let Status::Failed(reason) = status {
+                    self.notice = Some(format!(
+                        "Message to {} failed: {}",
+                        self.name(*peer),
+                        reason
+                    ));
+                }
+                self.statuses.insert(*id, status.clone());
+            }
+            Event::System(sys_msg) => self.handle_system(sys_msg),
+        }
+    }
+
+    fn handle_system(&mut self, msg: &SystemMessage) {
+        match msg {
+            SystemMessage::ServerInfo { peer, .. } => self.me = *peer,
+            SystemMessage::RoomCreated { room, name } => {
+                self.room_names.insert(*room, name.clone());
+                self.rooms.insert(*room);
+            }
+            SystemMessage::RoomJoined { room, peer } if *peer == self.me => {
+                self.rooms.insert(*room);
+            }
+            SystemMessage::RoomLeft { room, peer } if *peer == self.me => {
+                self.rooms.remove(room);
+            }
+            SystemMessage::Rooms(rooms) => {
+                self.room_names.extend(rooms.iter().cloned());
+                let names = rooms
+                    .iter()
+                    .map(|(room, name)| format!("{} #{}", room, name));
+                let names = names.collect::<Vec<_>>();
+                self.notice = Some(format!("Rooms: {}", names.join(", ")));
+            }
+            SystemMessage::Error { message, .. } => self.notice = Some(message.clone()),
+            SystemMessage::Shutdown { reason } => {
+                self.notice = Some(format!("Server shutting down: {}", reason));
+            }
+            _ => {}
+        }
+    }
+
+    // Everything that can be picked to chat in: everyone, each peer, then our rooms.
+    pub fn entries(&self) -> Vec<PeerId> {
+        let peers = self.peers.keys().copied();
+        let rooms = self.rooms.iter().copied();
+        [BROADCAST_PEER]
+            .into_iter()
+            .chain(peers)
+            .chain(rooms)
+            .collect()
+    }
+
+    pub fn name(&self, peer: PeerId) -> String {
+        if peer == BROADCAST_PEER {
+            return "Everyone".to_string();
+        }
+        if is_room(peer) {
+            return match self.room_names.get(&peer) {
+                Some(name) => format!("#{}", name),
+                None => format!("#{}", peer),
+            };
+        }
+        let name = self.peers.get(&peer).cloned();
+        let name = name.unwrap_or_else(|| peer.to_string());
+        if peer == self.me {
+            return format!("{} (you)", name);
+        }
+        name
+    }
+
+    // Rooms and everyone count as online.
+    pub fn is_online(&self, peer: PeerId) -> bool {
+        !is_person(peer) || self.online.contains(&peer)
+    }
+
+    pub fn unread(&self, peer: PeerId) -> usize {
+        self.unread.get(&peer).copied().unwrap_or(0)
+    }
+
+    pub fn select(&mut self, peer: PeerId) {
+        self.selected = Some(peer);
+        self.unread.remove(&peer);
+        self.scroll = 0;
+    }
+
+    // Moves the selection `by` entries up (negative) or down the list.
+    pub fn select_next(&mut self, by: isize) {
+        let entries = self.entries();
+        let current = self
+            .selected
+            .and_then(|selected| entries.iter().position(|peer| *peer == selected));
+        let next = match current {
+            Some(i) => i.saturating_add_signed(by).min(entries.len() - 1),
+            None => 0,
+        };
+        self.select(entries[next]);
+    }
+
+    // Tells the sender of what arrived in the open conversation that it has been read, once
+    // it is on screen. Without a connection the receipts wait until the next call.
+    pub fn mark_read(&self, client: &Client) {
+        if let Some(peer) = self.selected
+            && is_person(peer)
+        {
+            let _ = client.mark_read(peer);
+        }
+    }
+
+    // Which of `total` lines of the open conversation to show in `height` lines, keeping
+    // the scroll position within the conversation.
+    pub fn visible(&mut self, total: usize, height: usize) -> Range<usize> {
+        self.scroll = self.scroll.min(total.saturating_sub(height));
+        let end = total - self.scroll;
+        end.saturating_sub(height)..end
+    }
+
+    // The open conversation, a line per message.
+    pub fn conversation(&self, client: &Client) -> Vec<String> {
+        let Some(peer) = self.selected else {
+            return vec![];
+        };
+        client
+            .messages(peer)
+            .iter()
+            .map(|msg| {
+                let content = String::from_utf8_lossy(&msg.content);
+                if msg.origin.is_some_and(|origin| origin == self.me) {
+                    let status = msg.id.and_then(|id| {
+                        self.statuses
+                            .get(&id)
+                            .cloned()
+                            .or_else(|| client.status(id))
+                    });
+                    let status = match status {
+                        Some(Status::Sent) => " (sent)".to_string(),
+                        Some(Status::Queued) => " (queued)".to_string(),
+                        Some(Status::Delivered) => " (delivered)".to_string(),
+                        Some(Status::Read) => " (read)".to_string(),
+                        Some(Status::Failed(reason)) => format!(" (failed: {})", reason),
+                        None => String::new(),
+                    };
+                    format!("You: {}{}", content, status)
+                } else {
+                    format!("{}: {}", self.name(msg.sender()), content)
+                }
+            })
+            .collect()
+    }
+
+    // Sends what has been typed to the open conversation, or to the server as a command.
+    pub fn submit(&mut self, client: &Client) {
+        let line = self.input.take();
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        if let Some(command) = line.strip_prefix('/') {
+            match parse_command(command) {
+                Some(sys_msg) => {
+                    if let Err(e) = client.send_system(sys_msg) {
+                        self.notice = Some(format!("{}, command dropped", e));
+                    }
+                }
+                None => self.notice = Some(format!("Unknown command: /{}", command)),
+            }
+            return;
+        }
+        let Some(peer) = self.selected else {
+            self.notice = Some("Pick someone to talk to first".to_string());
+            return;
+        };
+        match client.send(peer, line.as_bytes()) {
+            Ok(_) => self.scroll = 0,
+            Err(e) => self.notice = Some(format!("Could not send to {}: {}", self.name(peer), e)),
+        }
+    }
+}
+
+// A peer rather than a room or everyone.
+fn is_person(peer: PeerId) -> bool {
+    peer != BROADCAST_PEER && !is_room(peer)
+}
+
+// Breaks `text` into lines no wider than `width`, between words where it can.
+pub fn wrap(text: &str, width: i32, measure: impl Fn(&str) -> i32) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let candidate = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", line, word)
+        };
+        if measure(&candidate) <= width {
+            line = candidate;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        // A single word too long for a line is split wherever it has to be
+        for c in word.chars() {
+            line.push(c);
+            if measure(&line) > width && line.chars().count() > 1 {
+                let c = line.pop().unwrap();
+                lines.push(std::mem::replace(&mut line, c.to_string()));
+            }
+        }
+    }
+    lines.push(line);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md_redis::parser::{Message, ROOM_BIT};
+
+    #[test]
+    fn test_line_editor() {
+        let mut input = LineEditor::default();
+        for c in "héllo".chars() {
+            input.insert(c);
+        }
+        input.left();
+        input.left();
+        input.backspace();
+        assert_eq!((input.text(), input.before_cursor()), ("hélo", "hé"));
+        input.home();
+        input.delete();
+        input.insert('H');
+        input.end();
+        input.right();
+        input.insert('!');
+        assert_eq!(input.take(), "Hélo!");
+        assert_eq!((input.text(), input.before_cursor()), ("", ""));
+        input.backspace();
+        input.delete();
+        assert_eq!(input.text(), "");
+    }
+
+    #[test]
+    fn test_unread_counts_and_entries() {
+        let mut view = ChatView::new();
+        view.handle(&Event::System(SystemMessage::ServerInfo {
+            protocol: 1,
+            peer: 1,
+        }));
+        let peers = vec![
+            (1, "alice".to_string()),
+            (2, "bob".to_string()),
+            (BROADCAST_PEER, String::new()),
+        ];
+        view.handle(&Event::Peers(peers));
+        let room = ROOM_BIT | 1;
+        view.handle(&Event::System(SystemMessage::RoomCreated {
+            room,
+            name: "general".to_string(),
+        }));
+        assert_eq!(view.entries(), vec![BROADCAST_PEER, 1, 2, room]);
+        assert_eq!(view.name(1), "alice (you)");
+        assert_eq!(view.name(room), "#general");
+
+        view.select(2);
+        view.handle(&Event::Message(Message::new(2, b"hi".to_vec(), false)));
+        view.handle(&Event::Message(Message::new(room, b"a".to_vec(), false)));
+        view.handle(&Event::Message(Message::new(room, b"b".to_vec(), false)));
+        assert_eq!((view.unread(2), view.unread(room)), (0, 2));
+        view.select_next(1);
+        assert_eq!((view.selected, view.unread(room)), (Some(room), 0));
+        view.select_next(1);
+        assert_eq!(view.selected, Some(room));
+        view.select_next(-10);
+        assert_eq!(view.selected, Some(BROADCAST_PEER));
+
+        view.scroll = 10;
+        assert_eq!(view.visible(5, 3), 0..3);
+        assert_eq!((view.scroll, view.visible(2, 3)), (2, 0..2));
+
+        view.handle(&Event::System(SystemMessage::RoomLeft { room, peer: 1 }));
+        assert_eq!(view.entries(), vec![BROADCAST_PEER, 1, 2]);
+    }
+
+    #[test]
+    fn test_offline_peers_stay_listed() {
+        let mut view = ChatView::new();
+        let peers = vec![(1, "alice".to_string()), (2, "bob".to_string())];
+        view.handle(&Event::Peers(peers));
+        view.select(2);
+        view.handle(&Event::Peers(vec![(1, "alice".to_string())]));
+        assert_eq!(view.entries(), vec![BROADCAST_PEER, 1, 2]);
+        assert!(view.is_online(1) && !view.is_online(2) && view.is_online(BROADCAST_PEER));
+        assert_eq!(view.name(2), "bob");
+        assert_eq!(view.notice.as_deref(), Some("bob went offline"));
+
+        // Only said once, and they're back under the same id
+        view.notice = None;
+        view.handle(&Event::Peers(vec![(1, "alice".to_string())]));
+        assert_eq!(view.notice, None);
+        view.handle(&Event::Peers(vec![
+            (1, "alice".to_string()),
+            (2, "bob".to_string()),
+        ]));
+        assert!(view.is_online(2));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let measure = |text: &str| text.chars().count() as i32;
+        assert_eq!(wrap("", 10, measure), vec![""]);
+        assert_eq!(
+            wrap("the quick brown fox", 10, measure),
+            vec!["the quick", "brown fox"]
+        );
+        assert_eq!(
+            wrap("a abcdefghijkl b", 5, measure),
+            vec!["a", "abcde", "fghij", "kl b"]
+        );
+    }
+}
@@ -1,176 +1,1387 @@
 use crate::{
-    parser::Message,
-    shared::{extract_message, write_message},
+    e2e::{self, E2eError, Identity},
+    parser::{
+        BROADCAST_PEER, ErrorCode, IdentityKey, Message, PeerId, SYSTEM_PEER, SystemMessage,
+        is_room,
+    },
+    shared::{ExtractError, FrameReader, Heartbeat, write_message},
+    tls::{self, ClientTls, Stream},
 };
-use raylib::prelude::*;
-use simplelog::{CombinedLogger, Config, LevelFilter, WriteLogger};
 use std::{
-    collections::HashMap,
-    fs::File,
-    net::TcpStream,
-    sync::{Arc, Mutex},
-    thread,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    io::{self, Write},
+    net::{Shutdown, TcpStream},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-struct ClientState {
-    peers: Mutex<HashMap<usize, Vec<Message>>>,
+// Messages asked for per /history command.
+pub const HISTORY_PAGE: u32 = 20;
+// Reconnect delays start around the base and double per failed attempt up to the max.
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+// How long the read thread idles when there is nothing to read.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Message ids remembered per peer to spot resends. A resend only repeats what the server
+// hadn't acknowledged when the sender's connection dropped, so the latest ids are enough.
+const SEEN_WINDOW: usize = 256;
+
+// How the client reaches the server and behaves once connected.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    // Where the client connects; every reconnect goes back here.
+    pub server_addr: String,
+    pub tls: Option<ClientTls>,
+    // Whether mark_read tells senders that their messages have been read.
+    pub read_receipts: bool,
+    pub heartbeat: Heartbeat,
+    // Keeps the identity key across runs; without it every client gets a fresh one.
+    pub identity: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            server_addr: "127.0.0.1:8000".to_string(),
+            tls: None,
+            read_receipts: true,
+            heartbeat: Heartbeat::default(),
+            identity: None,
+        }
+    }
+}
+
+// The current connection to the server, or None while there isn't one.
+type Link = Arc<Mutex<Option<Stream<TcpStream>>>>;
+
+// What the read thread reports to whoever holds the Client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Connection(ConnectionState),
+    // Everyone online, after every change; see Client::offline_peers for who left.
+    Peers(Vec<(PeerId, String)>),
+    // A direct, room or broadcast message, already decrypted. Resends are left out.
+    Message(Message),
+    // News about direct message `id` we sent to `peer`.
+    Status {
+        peer: PeerId,
+        id: u64,
+        status: Status,
+    },
+    // Everything else the server says, e.g. room replies, errors and history pages. The
+    // content of a HistoryEntry is decrypted like that of a Message.
+    System(SystemMessage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    // Lost or never made; the next attempt is after this long.
+    RetryingIn(Duration),
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    // Only direct messages are kept for a reconnect; anything else needs a connection.
+    NotConnected,
+    // The peer hasn't published an identity key yet, so we can't encrypt for them.
+    NoKey(PeerId),
+    Encrypt(E2eError),
+    Io(io::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::NotConnected => write!(f, "Not connected"),
+            SendError::NoKey(peer) => write!(f, "No key for peer {} yet", peer),
+            SendError::Encrypt(e) => write!(f, "Could not encrypt: {:?}", e),
+            SendError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Exponential backoff with jitter, so clients don't all come back at once after a restart.
+struct Backoff {
+    attempt: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            attempt: 0,
+            base,
+            max,
+        }
+    }
+
+    // Somewhere between half and all of base * 2^attempt, capped at `max`.
+    fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+        let jitter = getrandom::u32().unwrap_or(u32::MAX) as f64 / u32::MAX as f64;
+        ceiling.mul_f64(0.5 + jitter / 2.0)
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+// Where a message we sent has got to, as far as we've heard.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Sent,
+    Queued,
+    Delivered,
+    Read,
+    Failed(String),
+}
+
+// A direct message we sent. The encoded frames are kept until the server acknowledges it,
+// so it can be sent again after a reconnect.
+struct Outgoing {
+    // Order of sending, so resends keep it.
+    seq: usize,
+    peer: PeerId,
+    status: Status,
+    frames: Vec<Vec<u8>>,
+}
+
+pub(crate) struct ClientState {
+    peers: HashMap<PeerId, Vec<Message>>,
+    names: HashMap<PeerId, String>,
+    // Who is logged in right now. Peers who left stay in `names` with their conversations,
+    // so they can still be found and written to.
+    online: HashSet<PeerId>,
+    keys: HashMap<PeerId, IdentityKey>,
+    // Direct messages we sent until they have been read or have failed.
+    sent: HashMap<u64, Outgoing>,
+    // How many messages have been put in `sent`, for their seq.
+    sent_count: usize,
+    // How many of the messages to each peer failed, since they don't stay in `sent`.
+    failed: HashMap<PeerId, usize>,
+    // Ids of the latest direct messages received from each peer, oldest first, to spot
+    // resends.
+    seen: HashMap<PeerId, VecDeque<u64>>,
+    // Ids of direct messages received from each peer that haven't been marked read yet.
+    unread: HashMap<PeerId, Vec<u64>>,
+    // Our own id, once the server has told us.
+    me: PeerId,
 }
 
 impl ClientState {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         ClientState {
-            peers: Mutex::new(HashMap::new()),
+            peers: HashMap::new(),
+            names: HashMap::new(),
+            online: HashSet::new(),
+            keys: HashMap::new(),
+            sent: HashMap::new(),
+            sent_count: 0,
+            failed: HashMap::new(),
+            seen: HashMap::new(),
+            unread: HashMap::new(),
+            me: SYSTEM_PEER,
+        }
+    }
+
+    // Replaces the list of who is online; everyone missing from it is offline now.
+    fn set_peers(&mut self, peers: Vec<(PeerId, String)>) {
+        self.online.clear();
+        for (peer, name) in peers {
+            self.add_peer(peer, name);
         }
     }
 
-    fn set_peers(&self, peers: Vec<usize>) {
-        let mut peers_map = self.peers.lock().unwrap();
-        for peer in peers {
-            peers_map.entry(peer).or_default();
+    // An id that turns up under another name went to someone else while we weren't
+    // looking, so the conversation under it isn't theirs.
+    fn add_peer(&mut self, peer: PeerId, name: String) {
+        let previous = self.names.insert(peer, name.clone());
+        if previous.is_some_and(|previous| previous != name) {
+            self.peers.remove(&peer);
         }
+        self.peers.entry(peer).or_default();
+        self.online.insert(peer);
     }
 
-    fn add_message(&self, peer: usize, message: Message) {
-        let mut peers = self.peers.lock().unwrap();
-        peers.entry(peer).or_default().push(message);
+    fn remove_peer(&mut self, peer: PeerId) {
+        self.online.remove(&peer);
     }
 
-    fn get_messages(&self, peer: usize) -> Vec<Message> {
-        let peers = self.peers.lock().unwrap();
-        peers.get(&peer).cloned().unwrap_or_default()
+    // Everyone online with their name, rooms with an empty one, and everyone.
+    pub(crate) fn peer_list(&self) -> Vec<(PeerId, String)> {
+        let mut peers = self
+            .peers
+            .keys()
+            .filter(|peer| {
+                **peer == BROADCAST_PEER || is_room(**peer) || self.online.contains(peer)
+            })
+            .map(|peer| (*peer, self.names.get(peer).cloned().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        peers.sort_unstable();
+        peers
+    }
+
+    // Peers who have left, with the name they had.
+    pub(crate) fn offline_peers(&self) -> Vec<(PeerId, String)> {
+        let mut peers = self
+            .names
+            .iter()
+            .filter(|(peer, _)| !self.online.contains(peer))
+            .map(|(peer, name)| (*peer, name.clone()))
+            .collect::<Vec<_>>();
+        peers.sort_unstable();
+        peers
+    }
+
+    // Someone online goes before someone who had the name earlier.
+    fn peer_by_name(&self, name: &str) -> Option<PeerId> {
+        let mut matching = self
+            .names
+            .iter()
+            .filter(|(_, peer_name)| *peer_name == name)
+            .map(|(peer, _)| *peer);
+        matching
+            .clone()
+            .find(|peer| self.online.contains(peer))
+            .or_else(|| matching.next())
+    }
+
+    // Returns the key it replaces, if that was a different one.
+    fn set_key(&mut self, peer: PeerId, key: IdentityKey) -> Option<IdentityKey> {
+        let previous = self.keys.insert(peer, key);
+        previous.filter(|previous| *previous != key)
+    }
+
+    pub(crate) fn key(&self, peer: PeerId) -> Option<IdentityKey> {
+        self.keys.get(&peer).cloned()
     }
-}
 
-pub fn client(num: usize) {
-    let server_stream = TcpStream::connect("127.0.0.1:8000").expect("Could not connect to server");
-    server_stream.set_nonblocking(true).unwrap();
+    fn track(&mut self, id: u64, peer: PeerId, frames: Vec<Vec<u8>>) {
+        self.sent_count += 1;
+        let outgoing = Outgoing {
+            seq: self.sent_count,
+            peer,
+            status: Status::Sent,
+            frames,
+        };
+        self.sent.insert(id, outgoing);
+    }
 
-    CombinedLogger::init(vec![WriteLogger::new(
-        LevelFilter::Info,
-        Config::default(),
-        File::create(format!("client_{}.log", num)).unwrap(),
-    )])
-    .unwrap();
+    fn status(&self, id: u64) -> Option<Status> {
+        self.sent.get(&id).map(|outgoing| outgoing.status.clone())
+    }
 
-    let server_stream = Arc::new(Mutex::new(server_stream));
+    // Ignores ids we never sent or have finished with, so also a late Delivered after the
+    // message was already Read. Nothing more is expected after Read or Failed, so that is
+    // where the message is forgotten.
+    fn set_status(&mut self, id: u64, status: Status) -> bool {
+        let Some(outgoing) = self.sent.get_mut(&id) else {
+            return false;
+        };
+        match status {
+            Status::Read => {
+                self.sent.remove(&id);
+                return true;
+            }
+            Status::Failed(_) => {
+                let peer = outgoing.peer;
+                self.sent.remove(&id);
+                *self.failed.entry(peer).or_default() += 1;
+                return true;
+            }
+            _ => {}
+        }
+        outgoing.status = status;
+        // The server has it one way or another, so it won't be sent again.
+        outgoing.frames = vec![];
+        true
+    }
 
-    let client_state = Arc::new(Mutex::new(ClientState::new()));
+    // Everything the server hasn't acknowledged yet, in the order it was first sent.
+    fn unacked(&self) -> Vec<Vec<u8>> {
+        let mut unacked = self
+            .sent
+            .values()
+            .filter(|outgoing| outgoing.status == Status::Sent)
+            .collect::<Vec<&Outgoing>>();
+        unacked.sort_unstable_by_key(|outgoing| outgoing.seq);
+        unacked
+            .into_iter()
+            .flat_map(|outgoing| outgoing.frames.clone())
+            .collect()
+    }
 
-    let read_stream = Arc::clone(&server_stream);
-    let read_client_state = client_state.clone();
+    fn failed(&self, peer: PeerId) -> usize {
+        self.failed.get(&peer).copied().unwrap_or(0)
+    }
 
-    let (ui_tx, ui_rx) = std::sync::mpsc::channel::<SystemMessage>();
+    // False if this message from `peer` has been seen before.
+    fn first_sight(&mut self, peer: PeerId, id: u64) -> bool {
+        let seen = self.seen.entry(peer).or_default();
+        if seen.contains(&id) {
+            return false;
+        }
+        if seen.len() == SEEN_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(id);
+        true
+    }
 
-    let read_thread = thread::spawn({
-        move || {
-            loop {
-                let msg = extract_message(&mut read_stream.lock().unwrap());
-                if let Ok(msg) = msg {
-                    let peer = msg.peer;
+    fn add_unread(&mut self, peer: PeerId, ids: impl IntoIterator<Item = u64>) {
+        self.unread.entry(peer).or_default().extend(ids);
+    }
 
-                    if peer == 0 {
-                        if let Ok(SystemMessage::Peers(peers)) = read_system_message(msg) {
-                            update_peers(peers.clone(), read_client_state.clone());
-                            ui_tx
-                                .send(SystemMessage::Peers(peers.clone()))
-                                .expect("Failed to send peers to UI thread");
+    pub(crate) fn take_unread(&mut self, peer: PeerId) -> Vec<u64> {
+        self.unread.remove(&peer).unwrap_or_default()
+    }
+
+    fn add_message(&mut self, peer: PeerId, message: Message) {
+        self.peers.entry(peer).or_default().push(message);
+    }
+
+    fn me(&self) -> PeerId {
+        self.me
+    }
+
+    fn set_me(&mut self, me: PeerId) {
+        self.me = me;
+    }
+
+    fn get_messages(&self, peer: PeerId) -> Vec<Message> {
+        self.peers.get(&peer).cloned().unwrap_or_default()
+    }
+}
+
+// A connection to the server that logs in, keeps itself connected and decrypts what
+// arrives. Incoming traffic is read on a background thread and handed out through recv().
+pub struct Client {
+    state: Arc<Mutex<ClientState>>,
+    identity: Arc<Identity>,
+    link: Link,
+    // Set once the server has accepted our login on the current connection. Before that it
+    // would fail tracked messages, so they wait to go out with the unacknowledged ones.
+    logged_in: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    events: Mutex<Receiver<Event>>,
+    read_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Client {
+    // Connects to `addr` with the default settings.
+    pub fn connect(addr: &str, username: &str, password: &str) -> io::Result<Client> {
+        let config = ClientConfig {
+            server_addr: addr.to_string(),
+            ..ClientConfig::default()
+        };
+        Self::connect_with(config, username, password)
+    }
+
+    // Fails if the first connection can't be made; after that a lost connection is made
+    // again in the background, with the session resumed and unacknowledged messages resent.
+    pub fn connect_with(
+        config: ClientConfig,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Client> {
+        let ClientConfig {
+            server_addr,
+            tls,
+            read_receipts,
+            heartbeat,
+            identity,
+        } = config;
+        let identity = Arc::new(load_identity(identity)?);
+        let tls = match tls {
+            Some(tls) => Some((tls::client_config(&tls)?, tls.server_name)),
+            None => None,
+        };
+        // Sent first on every connection, so a reconnect picks up the same session.
+        let handshake = handshake(&identity, username, password)
+            .iter()
+            .flat_map(SystemMessage::to_frames)
+            .collect::<Vec<_>>();
+
+        let stream = connect(&server_addr, tls.as_ref(), handshake.clone())?;
+        log::info!("Connected to {}", server_addr);
+        let link: Link = Arc::new(Mutex::new(Some(stream)));
+        let client_state = Arc::new(Mutex::new(ClientState::new()));
+        let logged_in = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let (events_tx, events_rx) = mpsc::channel::<Event>();
+
+        let mut inbox = Inbox::new(client_state.clone(), identity.clone(), read_receipts);
+        let read_link = link.clone();
+        let read_logged_in = logged_in.clone();
+        let read_running = running.clone();
+        let read_thread = thread::spawn(move || {
+            let link = read_link;
+            let report = |state| {
+                let _ = events_tx.send(Event::Connection(state));
+            };
+            let mut backoff = Backoff::new(BACKOFF_BASE, BACKOFF_MAX);
+            while read_running.load(Ordering::SeqCst) {
+                // Only this thread puts a connection in, so senders need not wait on the
+                // connect; they queue or fail until it is there.
+                let connected = if link.lock().unwrap().is_some() {
+                    Ok(())
+                } else {
+                    report(ConnectionState::Connecting);
+                    connect(&server_addr, tls.as_ref(), handshake.clone())
+                        .map(|stream| *link.lock().unwrap() = Some(stream))
+                };
+                if let Err(e) = connected {
+                    let delay = backoff.next_delay();
+                    log::warn!(
+                        "Could not connect to {}: {}, retrying in {:?}",
+                        server_addr,
+                        e,
+                        delay
+                    );
+                    report(ConnectionState::RetryingIn(delay));
+                    sleep_while(&read_running, delay);
+                    continue;
+                }
+                log::info!("Connected to {}", server_addr);
+                report(ConnectionState::Connected);
+
+                let mut reader = FrameReader::new();
+                let mut name_in_use = false;
+                let mut last_seen = Instant::now();
+                let mut last_ping = last_seen;
+                while read_running.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    if heartbeat.is_enabled() {
+                        if now.duration_since(last_seen) >= heartbeat.timeout {
+                            log::warn!("Server stopped answering heartbeats");
+                            break;
+                        }
+                        if now.duration_since(last_seen.max(last_ping)) >= heartbeat.interval {
+                            send(&link, SystemMessage::Ping.to_frames());
+                            last_ping = now;
                         }
-                        continue;
                     }
-
-                    let content = String::from_utf8_lossy(&msg.content);
-                    log::info!("Received from {}: {}", peer, content);
+                    let messages = match link.lock().unwrap().as_mut() {
+                        Some(stream) => reader.read_from(stream),
+                        // A send found the connection broken
+                        None => Err(ExtractError::Closed),
+                    };
+                    match messages {
+                        Ok(messages) => {
+                            last_seen = Instant::now();
+                            for msg in messages {
+                                inbox.handle(msg);
+                            }
+                            pass_on(&mut inbox, &link, &events_tx);
+                            // Backing off is over once we're logged in, not just connected
+                            match inbox.login.take() {
+                                Some(Login::Accepted) => {
+                                    backoff.reset();
+                                    // Messages sent while logging in were only tracked
+                                    read_logged_in.store(true, Ordering::SeqCst);
+                                    let unacked = inbox.state.lock().unwrap().unacked();
+                                    send(&link, unacked);
+                                }
+                                Some(Login::NameInUse) => {
+                                    log::warn!("Name still in use, maybe by our old connection");
+                                    name_in_use = true;
+                                    break;
+                                }
+                                None => {}
+                            }
+                        }
+                        Err(ExtractError::NotReady) => thread::sleep(POLL_INTERVAL),
+                        Err(ExtractError::InvalidMessage(e)) => {
+                            log::warn!("Invalid message from server: {:?}", e);
+                        }
+                        Err(ExtractError::Closed) => {
+                            log::info!("Connection closed by server");
+                            break;
+                        }
+                        Err(ExtractError::IOError(e)) => {
+                            log::warn!("Connection lost: {}", e);
+                            break;
+                        }
+                    }
+                }
+                if !read_running.load(Ordering::SeqCst) {
+                    // close() says goodbye on the connection
+                    break;
+                }
+                read_logged_in.store(false, Ordering::SeqCst);
+                *link.lock().unwrap() = None;
+                // Until the server notices the old connection is gone
+                if name_in_use {
+                    let delay = backoff.next_delay();
+                    report(ConnectionState::RetryingIn(delay));
+                    sleep_while(&read_running, delay);
                 }
             }
+        });
+
+        Ok(Client {
+            state: client_state,
+            identity,
+            link,
+            logged_in,
+            running,
+            events: Mutex::new(events_rx),
+            read_thread: Mutex::new(Some(read_thread)),
+        })
+    }
+
+    // Sends `content` to a peer, a room or BROADCAST_PEER. Direct messages are encrypted
+    // and get an id, returned here, that later Status events refer to.
+    // The message joins the conversation with `peer`, with ourselves as its origin.
+    pub fn send(&self, peer: PeerId, content: &[u8]) -> Result<Option<u64>, SendError> {
+        let mut state = self.state.lock().unwrap();
+        let msg = outgoing(&mut state, &self.identity, peer, content, None)?;
+        drop(state);
+        let frames = Message::encode_with_id(msg.peer, None, msg.id, &msg.content);
+        let written = match msg.id {
+            Some(_) if !self.logged_in.load(Ordering::SeqCst) => false,
+            _ => send(&self.link, frames),
+        };
+        if !written {
+            // Only tracked messages are resent once logged in again.
+            let Some(id) = msg.id else {
+                return Err(SendError::NotConnected);
+            };
+            log::info!("Not logged in, message {} goes out once we are", id);
         }
-    });
+        let mut state = self.state.lock().unwrap();
+        let mut ours = Message::new(peer, content.to_vec(), false);
+        ours.origin = Some(state.me());
+        ours.id = msg.id;
+        state.add_message(peer, ours);
+        Ok(msg.id)
+    }
 
-    let write_stream = Arc::clone(&server_stream);
-    let write_thread = thread::spawn(move || {
-        let mut input = String::new();
-        loop {
-            input.clear();
-            std::io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read line");
-            let peer = input
-                .trim()
-                .to_string()
-                .parse::<usize>()
-                .expect("Invalid peer number");
-            input.clear();
-            std::io::stdin()
-                .read_line(&mut input)
-                .expect("Failed to read line");
-            let msg = input.trim().to_string();
-            log::info!("Sending to {}: {}", peer, msg);
-            let msg = Message::encode(peer, msg.as_bytes().to_vec().as_ref());
-            write_message(&mut write_stream.lock().unwrap(), msg);
-        }
-    });
-
-    let (mut rl, thread) = raylib::init().size(640, 480).title("Hello, World").build();
-    let mut peers = vec![];
-    while !rl.window_should_close() {
-        let mut d = rl.begin_drawing(&thread);
-
-        if let Ok(msg) = ui_rx.try_recv() {
-            match msg {
-                SystemMessage::Peers(peers_list) => {
-                    log::info!("Updated peers: {:?}", peers);
-                    peers = peers_list
-                        .iter()
-                        .map(|&p| p.to_string())
-                        .collect::<Vec<String>>()
+    // Room management, history queries and other requests to the server.
+    pub fn send_system(&self, msg: SystemMessage) -> Result<(), SendError> {
+        if !send(&self.link, msg.to_frames()) {
+            return Err(SendError::NotConnected);
+        }
+        Ok(())
+    }
+
+    // Waits for the next event; None once the client has been closed.
+    pub fn recv(&self) -> Option<Event> {
+        self.events.lock().unwrap().recv().ok()
+    }
+
+    pub fn try_recv(&self) -> Option<Event> {
+        self.events.lock().unwrap().try_recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        self.events.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    // Everyone online with their name, rooms with an empty one, and everyone.
+    pub fn peers(&self) -> Vec<(PeerId, String)> {
+        self.state.lock().unwrap().peer_list()
+    }
+
+    // Peers who have left since we saw them. Their conversations are kept, and messages to
+    // them wait on the server until they are back.
+    pub fn offline_peers(&self) -> Vec<(PeerId, String)> {
+        self.state.lock().unwrap().offline_peers()
+    }
+
+    // A peer given by id, by username, or "*" for everyone.
+    pub fn resolve_peer(&self, peer: &str) -> Option<PeerId> {
+        match peer {
+            "*" => Some(BROADCAST_PEER),
+            peer => peer
+                .parse::<PeerId>()
+                .ok()
+                .or_else(|| self.state.lock().unwrap().peer_by_name(peer)),
+        }
+    }
+
+    // The conversation with `peer`, or in room `peer`, so far: what arrived and what we sent.
+    pub fn messages(&self, peer: PeerId) -> Vec<Message> {
+        self.state.lock().unwrap().get_messages(peer)
+    }
+
+    // Tells `peer` that the direct messages from them that arrived since the last call have
+    // been read; call it once their conversation is on screen. Does nothing with read
+    // receipts turned off. Without a connection the receipts wait for the next call.
+    pub fn mark_read(&self, peer: PeerId) -> Result<(), SendError> {
+        let ids = self.state.lock().unwrap().take_unread(peer);
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let frames = ids
+            .iter()
+            .flat_map(|id| SystemMessage::Read { peer, id: *id }.to_frames())
+            .collect();
+        if !send(&self.link, frames) {
+            self.state.lock().unwrap().add_unread(peer, ids);
+            return Err(SendError::NotConnected);
+        }
+        Ok(())
+    }
+
+    // Where a direct message we sent has got to, until it has been read or has failed; the
+    // Status events tell about those.
+    pub fn status(&self, id: u64) -> Option<Status> {
+        self.state.lock().unwrap().status(id)
+    }
+
+    // Our own id, or SYSTEM_PEER until the server has told us.
+    pub fn me(&self) -> PeerId {
+        self.state.lock().unwrap().me()
+    }
+
+    // How many of our direct messages to `peer` could not be delivered.
+    pub fn failed(&self, peer: PeerId) -> usize {
+        self.state.lock().unwrap().failed(peer)
+    }
+
+    pub fn key(&self, peer: PeerId) -> Option<IdentityKey> {
+        self.state.lock().unwrap().key(peer)
+    }
+
+    // Our own key's fingerprint, for comparing with what peers see.
+    pub fn fingerprint(&self) -> String {
+        e2e::fingerprint(&self.identity.public_key())
+    }
+
+    // Stops the read thread and drops the connection. Closing twice does nothing.
+    pub fn close(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        let Some(read_thread) = self.read_thread.lock().unwrap().take() else {
+            return;
+        };
+        read_thread.join().expect("Read thread panicked");
+        if let Some(mut stream) = self.link.lock().unwrap().take() {
+            stream.send_close_notify();
+            let _ = stream.flush();
+            let _ = stream.get_ref().shutdown(Shutdown::Both);
+        }
+        log::info!("Client terminated.");
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Opens a fresh connection and writes `handshake` before the socket goes non-blocking,
+// so the TLS handshake and the login complete first.
+fn connect(
+    addr: &str,
+    tls: Option<&(Arc<rustls::ClientConfig>, String)>,
+    handshake: Vec<Vec<u8>>,
+) -> io::Result<Stream<TcpStream>> {
+    let sock = TcpStream::connect(addr)?;
+    let mut stream = match tls {
+        Some((config, server_name)) => Stream::client(sock, config.clone(), server_name)?,
+        None => Stream::Plain(sock),
+    };
+    write_message(&mut stream, handshake)?;
+    stream.get_ref().set_nonblocking(true)?;
+    Ok(stream)
+}
+
+// Writes to the server if connected. A failed write drops the connection; the read thread
+// then reconnects and resends whatever wasn't acknowledged.
+fn send(link: &Link, msg: Vec<Vec<u8>>) -> bool {
+    let mut link = link.lock().unwrap();
+    let Some(stream) = link.as_mut() else {
+        return false;
+    };
+    if let Err(e) = write_message(stream, msg) {
+        log::warn!("Lost connection while sending: {}", e);
+        let _ = stream.get_ref().shutdown(Shutdown::Both);
+        *link = None;
+        return false;
+    }
+    true
+}
+
+fn sleep_while(running: &AtomicBool, duration: Duration) {
+    let until = Instant::now() + duration;
+    while running.load(Ordering::SeqCst) && Instant::now() < until {
+        thread::sleep(POLL_INTERVAL.min(until - Instant::now()));
+    }
+}
+
+// Sends the replies the inbox asked for and hands its events to the Client.
+fn pass_on(inbox: &mut Inbox, link: &Link, events: &Sender<Event>) {
+    for reply in inbox.replies.drain(..) {
+        send(link, reply.to_frames());
+    }
+    for event in inbox.events.drain(..) {
+        // Nobody may be listening any more while the client shuts down.
+        let _ = events.send(event);
+    }
+}
+
+// The identity key at `path`, or a fresh one without a path.
+pub(crate) fn load_identity(path: Option<PathBuf>) -> io::Result<Identity> {
+    let identity = match path {
+        Some(path) => Identity::load_or_create(path)?,
+        None => Identity::generate()?,
+    };
+    log::info!(
+        "Our key fingerprint: {}",
+        e2e::fingerprint(&identity.public_key())
+    );
+    Ok(identity)
+}
+
+// What every connection starts with: logging in, then publishing our key.
+pub(crate) fn handshake(identity: &Identity, username: &str, password: &str) -> Vec<SystemMessage> {
+    let login = SystemMessage::Login {
+        username: username.to_string(),
+        password: password.to_string(),
+    };
+    vec![login, SystemMessage::PublishKey(identity.public_key())]
+}
+
+// Seals a direct message to `peer` and tracks it under `id`, or a new id without one. Room,
+// broadcast and system messages go out as they are.
+pub(crate) fn outgoing(
+    state: &mut ClientState,
+    identity: &Identity,
+    peer: PeerId,
+    content: &[u8],
+    id: Option<u64>,
+) -> Result<Message, SendError> {
+    // Only direct messages are acknowledged, so only they are tracked and resent.
+    if peer == SYSTEM_PEER || peer == BROADCAST_PEER || is_room(peer) {
+        return Ok(Message::new(peer, content.to_vec(), false));
+    }
+    let key = state.key(peer).ok_or(SendError::NoKey(peer))?;
+    let sealed = identity.seal(&key, content).map_err(SendError::Encrypt)?;
+    let id = match id {
+        Some(id) => id,
+        None => getrandom::u64().map_err(|e| SendError::Io(e.into()))?,
+    };
+    state.track(
+        id,
+        peer,
+        Message::encode_with_id(peer, None, Some(id), &sealed),
+    );
+    let mut msg = Message::new(peer, sealed, false);
+    msg.id = Some(id);
+    Ok(msg)
+}
+
+// What the server made of a login.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Login {
+    Accepted,
+    // Someone is logged in under the name, possibly our own dropped connection.
+    NameInUse,
+}
+
+// Everything the server sends ends up here; it outlives any one connection. What it makes
+// of it collects in `events`, what the server should be told back in `replies` and how
+// the login went in `login`.
+pub(crate) struct Inbox {
+    state: Arc<Mutex<ClientState>>,
+    identity: Arc<Identity>,
+    read_receipts: bool,
+    pub(crate) events: VecDeque<Event>,
+    pub(crate) replies: Vec<SystemMessage>,
+    pub(crate) login: Option<Login>,
+}
+
+impl Inbox {
+    pub(crate) fn new(
+        state: Arc<Mutex<ClientState>>,
+        identity: Arc<Identity>,
+        read_receipts: bool,
+    ) -> Self {
+        Inbox {
+            state,
+            identity,
+            read_receipts,
+            events: VecDeque::new(),
+            replies: vec![],
+            login: None,
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.events.push_back(event);
+    }
+
+    // Only changes are passed on, so a late Delivered after Read is not reported.
+    fn set_status(&mut self, peer: PeerId, id: u64, status: Status) -> bool {
+        if !self.state.lock().unwrap().set_status(id, status.clone()) {
+            return false;
+        }
+        self.emit(Event::Status { peer, id, status });
+        true
+    }
+
+    pub(crate) fn handle(&mut self, msg: Message) {
+        let peer = msg.peer;
+        if peer == SYSTEM_PEER {
+            match SystemMessage::decode(&msg.content) {
+                Ok(sys_msg) => self.handle_system(sys_msg),
+                Err(e) => {
+                    log::warn!(
+                        "Unreadable system message {:?}: {}",
+                        e,
+                        String::from_utf8_lossy(&msg.content)
+                    );
                 }
             }
+            return;
         }
-        d.clear_background(Color::WHITE);
 
-        for (i, peer) in peers.iter().enumerate() {
-            d.draw_text(
-                &format!("Peer {}: {}", i, peer),
-                12,
-                12 + i as i32 * 20,
-                20,
-                Color::BLACK,
+        let mut msg = msg;
+        let opened = open(
+            &self.identity,
+            &self.state.lock().unwrap(),
+            peer,
+            msg.content,
+            false,
+        );
+        match opened {
+            Ok(plaintext) => msg.content = plaintext,
+            Err(e) => {
+                log::warn!("Could not decrypt message from {}: {:?}", peer, e);
+                return;
+            }
+        }
+        if let Some(id) = msg.id
+            && peer != BROADCAST_PEER
+            && !is_room(peer)
+        {
+            let mut state = self.state.lock().unwrap();
+            // A resend after a reconnect; the first copy already arrived.
+            if !state.first_sight(peer, id) {
+                return;
+            }
+            // The receipt waits for mark_read, once whoever uses the client has shown it.
+            if self.read_receipts {
+                state.add_unread(peer, [id]);
+            }
+        }
+        let content = String::from_utf8_lossy(&msg.content);
+        if peer == BROADCAST_PEER {
+            log::info!("Broadcast from {}: {}", msg.sender(), content);
+        } else if is_room(peer) {
+            log::info!(
+                "Received in room {} from {}: {}",
+                peer,
+                msg.sender(),
+                content
             );
+        } else {
+            log::info!("Received from {}: {}", peer, content);
         }
+        self.state.lock().unwrap().add_message(peer, msg.clone());
+        self.emit(Event::Message(msg));
+    }
 
+    fn handle_system(&mut self, msg: SystemMessage) {
+        match msg {
+            update @ (SystemMessage::Peers(_)
+            | SystemMessage::PeerJoined { .. }
+            | SystemMessage::PeerLeft(_)) => {
+                let peers = update_peers(update, self.state.clone());
+                self.emit(Event::Peers(peers));
+            }
+            SystemMessage::PeerKey { peer, key } => {
+                if self.state.lock().unwrap().set_key(peer, key).is_some() {
+                    log::warn!("Key for peer {} changed, verify it again", peer);
+                }
+                log::info!("Peer {} key fingerprint: {}", peer, e2e::fingerprint(&key));
+                self.emit(Event::System(SystemMessage::PeerKey { peer, key }));
+            }
+            SystemMessage::Queued { peer, id } => {
+                log::info!("Peer {} is offline, message queued until they return", peer);
+                match id {
+                    Some(id) => {
+                        self.set_status(peer, id, Status::Queued);
+                    }
+                    None => self.emit(Event::System(SystemMessage::Queued { peer, id })),
+                }
+            }
+            SystemMessage::Dropped { peer, id } => {
+                log::warn!(
+                    "Peer {} is not keeping up, the server dropped a message to it",
+                    peer
+                );
+                match id {
+                    Some(id) => {
+                        let failed = Status::Failed("Dropped, recipient too slow".to_string());
+                        self.set_status(peer, id, failed);
+                    }
+                    None => self.emit(Event::System(SystemMessage::Dropped { peer, id })),
+                }
+            }
+            SystemMessage::Delivered { peer, id } => {
+                if self.set_status(peer, id, Status::Delivered) {
+                    log::info!("Message {} delivered to {}", id, peer);
+                }
+            }
+            SystemMessage::Read { peer, id } => {
+                if self.set_status(peer, id, Status::Read) {
+                    log::info!("Message {} read by {}", id, peer);
+                }
+            }
+            SystemMessage::Failed {
+                peer,
+                id,
+                code,
+                message,
+            } => {
+                log::warn!(
+                    "Message {} to {} failed ({:?}): {}",
+                    id,
+                    peer,
+                    code,
+                    message
+                );
+                self.set_status(peer, id, Status::Failed(message));
+            }
+            SystemMessage::ServerInfo { protocol, peer } => {
+                log::info!("Logged in as peer {}", peer);
+                self.login = Some(Login::Accepted);
+                self.state.lock().unwrap().set_me(peer);
+                self.emit(Event::System(SystemMessage::ServerInfo { protocol, peer }));
+            }
+            SystemMessage::HistoryEntry {
+                id,
+                timestamp,
+                src,
+                dst,
+                content,
+            } => {
+                let state = self.state.lock().unwrap();
+                let sent = src == state.me();
+                // The conversation it belongs to, which for a room is the room.
+                let other = if sent || dst == BROADCAST_PEER || is_room(dst) {
+                    dst
+                } else {
+                    src
+                };
+                let opened = open(&self.identity, &state, other, content, sent);
+                drop(state);
+                match opened {
+                    Ok(content) => {
+                        log::info!(
+                            "History #{} {} -> {}: {}",
+                            id,
+                            src,
+                            dst,
+                            String::from_utf8_lossy(&content)
+                        );
+                        self.emit(Event::System(SystemMessage::HistoryEntry {
+                            id,
+                            timestamp,
+                            src,
+                            dst,
+                            content,
+                        }));
+                    }
+                    Err(e) => log::warn!("Could not decrypt history #{}: {:?}", id, e),
+                }
+            }
+            SystemMessage::Ping => self.replies.push(SystemMessage::Pong),
+            SystemMessage::Pong => {}
+            SystemMessage::Error { code, message } => {
+                log::warn!("Server error {:?}: {}", code, message);
+                if code == ErrorCode::NameInUse {
+                    self.login = Some(Login::NameInUse);
+                }
+                self.emit(Event::System(SystemMessage::Error { code, message }));
+            }
+            sys_msg => {
+                log::info!("System message: {:?}", sys_msg);
+                self.emit(Event::System(sys_msg));
+            }
+        }
     }
+}
 
-    read_thread.join().expect("Read thread panicked");
-    write_thread.join().expect("Write thread panicked");
-    // ui_thread.join().expect("UI thread panicked");
-    log::info!("Client terminated.");
+// Opens a direct message with `peer`'s published key, one we sent to them if `sent`. Once we
+// have that key, content in the clear is refused, as only the server could have put it there;
+// rooms and broadcasts are never sealed.
+fn open(
+    identity: &Identity,
+    state: &ClientState,
+    peer: PeerId,
+    content: Vec<u8>,
+    sent: bool,
+) -> Result<Vec<u8>, E2eError> {
+    if peer == BROADCAST_PEER || is_room(peer) {
+        return Ok(content);
+    }
+    match state.key(peer) {
+        Some(key) if sent => identity.open_sent(&key, &content),
+        Some(key) => identity.open(&key, &content),
+        // A peer without a key, e.g. a legacy client, can only write in the clear.
+        None if !e2e::is_sealed(&content) => Ok(content),
+        None => Err(E2eError::BadKey),
+    }
 }
 
-#[derive(Debug)]
-enum SystemMessageError {
-    NotASysMsg,
+// Room management and history paging typed in place of a peer number, e.g. "/join 2147483649".
+pub fn parse_command(command: &str) -> Option<SystemMessage> {
+    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+    let arg = arg.trim();
+    match name {
+        "create" => Some(SystemMessage::CreateRoom {
+            name: arg.to_string(),
+        }),
+        "join" => arg.parse().ok().map(SystemMessage::JoinRoom),
+        "leave" => arg.parse().ok().map(SystemMessage::LeaveRoom),
+        "rooms" => Some(SystemMessage::ListRooms),
+        // "/history <peer> [before id]" pages back through a conversation.
+        "history" => {
+            let mut args = arg.split_whitespace();
+            let peer = args.next()?.parse().ok()?;
+            let before = match args.next() {
+                Some(before) => Some(before.parse().ok()?),
+                None => None,
+            };
+            Some(SystemMessage::HistoryQuery {
+                peer,
+                limit: HISTORY_PAGE,
+                before,
+            })
+        }
+        _ => None,
+    }
 }
-enum SystemMessage {
-    Peers(Vec<usize>),
+
+// Applies a snapshot or a join/leave delta and returns the resulting peer list.
+fn update_peers(
+    update: SystemMessage,
+    client_state: Arc<Mutex<ClientState>>,
+) -> Vec<(PeerId, String)> {
+    let mut client_state = client_state.lock().unwrap();
+    match update {
+        SystemMessage::Peers(peers) => client_state.set_peers(peers),
+        SystemMessage::PeerJoined { peer, name } => client_state.add_peer(peer, name),
+        SystemMessage::PeerLeft(peer) => client_state.remove_peer(peer),
+        _ => {}
+    }
+    client_state.peer_list()
 }
 
-fn read_system_message(message: Message) -> Result<SystemMessage, SystemMessageError> {
-    let peer = message.peer;
-    if peer != 0 {
-        return Err(SystemMessageError::NotASysMsg);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, ServerConfig};
+
+    fn named(peers: &[(PeerId, &str)]) -> Vec<(PeerId, String)> {
+        peers
+            .iter()
+            .map(|(peer, name)| (*peer, name.to_string()))
+            .collect()
     }
-    let content = String::from_utf8_lossy(&message.content);
-    if content.starts_with("PEERS:") {
-        let peers: Vec<usize> = content[6..]
-            .split(',')
-            .filter_map(|s| s.trim().parse().ok())
-            .collect();
-        Ok(SystemMessage::Peers(peers))
-    } else {
-        log::info!("System message from {}: {}", peer, content);
-        Err(SystemMessageError::NotASysMsg)
+
+    #[test]
+    fn test_peer_updates() {
+        let state = Arc::new(Mutex::new(ClientState::new()));
+        let snapshot = SystemMessage::Peers(named(&[(3, "c"), (1, "a"), (2, "b")]));
+        let peers = update_peers(snapshot, state.clone());
+        assert_eq!(peers, named(&[(1, "a"), (2, "b"), (3, "c")]));
+        let joined = SystemMessage::PeerJoined {
+            peer: 5,
+            name: "e".to_string(),
+        };
+        assert_eq!(
+            update_peers(joined, state.clone()),
+            named(&[(1, "a"), (2, "b"), (3, "c"), (5, "e")])
+        );
+        assert_eq!(
+            update_peers(SystemMessage::PeerLeft(2), state.clone()),
+            named(&[(1, "a"), (3, "c"), (5, "e")])
+        );
+        assert_eq!(state.lock().unwrap().peer_by_name("e"), Some(5));
+        assert_eq!(state.lock().unwrap().peer_by_name("b"), Some(2));
+        assert_eq!(state.lock().unwrap().offline_peers(), named(&[(2, "b")]));
+    }
+
+    #[test]
+    fn test_departed_peers_are_kept() {
+        let mut state = ClientState::new();
+        state.set_peers(named(&[(1, "a"), (2, "b"), (3, "c")]));
+        state.add_message(2, Message::new(2, b"hi".to_vec(), false));
+        state.add_message(3, Message::new(3, b"yo".to_vec(), false));
+        state.set_peers(named(&[(1, "a"), (4, "d")]));
+        assert_eq!(state.peer_list(), named(&[(1, "a"), (4, "d")]));
+        assert_eq!(state.offline_peers(), named(&[(2, "b"), (3, "c")]));
+        assert_eq!(state.get_messages(2).len(), 1);
+        assert_eq!(state.peer_by_name("b"), Some(2));
+
+        // Back under their id, or under a new one with the name they had
+        state.add_peer(2, "b".to_string());
+        assert_eq!(state.get_messages(2).len(), 1);
+        state.add_peer(5, "c".to_string());
+        assert_eq!(state.peer_by_name("c"), Some(5));
+        // Someone else got the id, the conversation wasn't with them
+        state.add_peer(3, "e".to_string());
+        assert!(state.get_messages(3).is_empty());
     }
-}
 
-fn update_peers(peers: Vec<usize>, client_state: Arc<Mutex<ClientState>>) {
-    client_state.lock().unwrap().set_peers(peers);
+    #[test]
+    fn test_key_changes_are_reported() {
+        let mut state = ClientState::new();
+        assert_eq!(state.set_key(2, [1; 32]), None);
+        assert_eq!(state.set_key(2, [1; 32]), None);
+        assert_eq!(state.set_key(2, [9; 32]), Some([1; 32]));
+        assert_eq!(state.key(2), Some([9; 32]));
+        assert_eq!(state.key(3), None);
+    }
+
+    #[test]
+    fn test_unsealed_direct_messages_are_refused() {
+        let mut state = ClientState::new();
+        let ours = Identity::generate().unwrap();
+        let theirs = Identity::generate().unwrap();
+        // Nothing to check against until the peer has published a key
+        assert_eq!(
+            open(&ours, &state, 2, b"hi".to_vec(), false),
+            Ok(b"hi".to_vec())
+        );
+        state.set_key(2, theirs.public_key());
+        assert_eq!(
+            open(&ours, &state, 2, b"hi".to_vec(), false),
+            Err(E2eError::NotSealed)
+        );
+        let sealed = theirs.seal(&ours.public_key(), b"hi").unwrap();
+        assert_eq!(open(&ours, &state, 2, sealed, false), Ok(b"hi".to_vec()));
+        // Our own message, back from history, is ours and not theirs
+        let mine = ours.seal(&theirs.public_key(), b"yo").unwrap();
+        assert_eq!(
+            open(&ours, &state, 2, mine.clone(), false),
+            Err(E2eError::DecryptFailed)
+        );
+        assert_eq!(open(&ours, &state, 2, mine, true), Ok(b"yo".to_vec()));
+        // Rooms and broadcasts are never sealed
+        for peer in [BROADCAST_PEER, crate::parser::ROOM_BIT | 1] {
+            assert_eq!(
+                open(&ours, &state, peer, b"all".to_vec(), false),
+                Ok(b"all".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_status() {
+        let mut state = ClientState::new();
+        state.track(7, 2, vec![]);
+        state.track(8, 2, vec![]);
+        assert_eq!(state.status(7), Some(Status::Sent));
+        assert!(state.set_status(7, Status::Delivered));
+        assert_eq!(state.status(7), Some(Status::Delivered));
+        assert!(state.set_status(7, Status::Read));
+        assert!(!state.set_status(7, Status::Delivered));
+        assert_eq!(state.status(7), None);
+        assert!(!state.set_status(9, Status::Delivered));
+        assert_eq!(state.failed(2), 0);
+        assert!(state.set_status(8, Status::Failed("Peer not found".to_string())));
+        assert_eq!(state.failed(2), 1);
+        // Finished messages are not kept around
+        assert!(state.sent.is_empty());
+    }
+
+    #[test]
+    fn test_unacked_messages_are_resent_in_order() {
+        let mut state = ClientState::new();
+        for id in [30, 10, 20] {
+            state.track(id, 2, vec![vec![id as u8]]);
+        }
+        state.set_status(10, Status::Delivered);
+        assert_eq!(state.unacked(), vec![vec![30], vec![20]]);
+        state.set_status(30, Status::Queued);
+        assert_eq!(state.unacked(), vec![vec![20]]);
+        // Later messages still go after earlier ones once some are forgotten
+        state.set_status(10, Status::Read);
+        state.set_status(30, Status::Read);
+        state.track(40, 2, vec![vec![40]]);
+        assert_eq!(state.unacked(), vec![vec![20], vec![40]]);
+
+        assert!(state.first_sight(2, 5));
+        assert!(!state.first_sight(2, 5));
+        assert!(state.first_sight(3, 5));
+        // Only the latest ids per peer are kept
+        for id in 0..SEEN_WINDOW as u64 {
+            state.first_sight(2, 100 + id);
+        }
+        assert!(state.first_sight(2, 5));
+        assert!(!state.first_sight(2, 99 + SEEN_WINDOW as u64));
+        assert_eq!(state.seen[&2].len(), SEEN_WINDOW);
+    }
+
+    // Skips events until one matches.
+    fn wait_for(client: &Client, pred: impl Fn(&Event) -> bool) -> Event {
+        loop {
+            let event = client
+                .recv_timeout(Duration::from_secs(5))
+                .expect("no matching event arrived");
+            if pred(&event) {
+                return event;
+            }
+        }
+    }
+
+    #[test]
+    fn test_clients_exchange_direct_messages() {
+        let server = Server::bind(ServerConfig {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            ..ServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let alice = Client::connect(&addr, "alice", "").unwrap();
+        wait_for(&alice, |e| {
+            *e == Event::Connection(ConnectionState::Connected)
+        });
+        let bob = Client::connect(&addr, "bob", "").unwrap();
+        wait_for(&alice, |e| {
+            matches!(e, Event::System(SystemMessage::PeerKey { peer: 2, .. }))
+        });
+        wait_for(&bob, |e| {
+            matches!(e, Event::System(SystemMessage::PeerKey { peer: 1, .. }))
+        });
+        assert_eq!(alice.resolve_peer("bob"), Some(2));
+        assert_eq!(
+            alice.peers(),
+            vec![(1, "alice".to_string()), (2, "bob".to_string())]
+        );
+
+        let id = alice
+            .send(2, b"hello bob")
+            .unwrap()
+            .expect("direct messages get an id");
+        let Event::Message(msg) = wait_for(&bob, |e| matches!(e, Event::Message(_))) else {
+            unreachable!();
+        };
+        assert_eq!(
+            (msg.peer, msg.content.as_slice()),
+            (1, b"hello bob".as_slice())
+        );
+        assert_eq!(bob.messages(1), vec![msg]);
+        bob.mark_read(1).unwrap();
+        assert_eq!(alice.me(), 1);
+        assert_eq!(alice.messages(2)[0].origin, Some(1));
+        wait_for(&alice, |e| {
+            *e == Event::Status {
+                peer: 2,
+                id,
+                status: Status::Read,
+            }
+        });
+        assert!(matches!(alice.send(3, b"who?"), Err(SendError::NoKey(3))));
+
+        alice.close();
+        bob.close();
+        while alice.recv().is_some() {}
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_retries_while_name_in_use() {
+        let server = Server::bind(ServerConfig {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            ..ServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let first = Client::connect(&addr, "bob", "").unwrap();
+        wait_for(&first, |e| {
+            matches!(e, Event::System(SystemMessage::ServerInfo { .. }))
+        });
+        let second = Client::connect(&addr, "bob", "").unwrap();
+        wait_for(&second, |e| {
+            matches!(e, Event::Connection(ConnectionState::RetryingIn(_)))
+        });
+        first.close();
+        wait_for(&second, |e| {
+            matches!(e, Event::System(SystemMessage::ServerInfo { .. }))
+        });
+
+        second.close();
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay();
+            let ceiling = Duration::from_millis(ceiling);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_parse_room_commands() {
+        assert_eq!(
+            parse_command("create general"),
+            Some(SystemMessage::CreateRoom {
+                name: "general".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command("join 2147483649"),
+            Some(SystemMessage::JoinRoom(2147483649))
+        );
+        assert_eq!(
+            parse_command("leave 2147483649"),
+            Some(SystemMessage::LeaveRoom(2147483649))
+        );
+        assert_eq!(parse_command("rooms"), Some(SystemMessage::ListRooms));
+        assert_eq!(
+            parse_command("history 2"),
+            Some(SystemMessage::HistoryQuery {
+                peer: 2,
+                limit: HISTORY_PAGE,
+                before: None
+            })
+        );
+        assert_eq!(
+            parse_command("history 2 41"),
+            Some(SystemMessage::HistoryQuery {
+                peer: 2,
+                limit: HISTORY_PAGE,
+                before: Some(41)
+            })
+        );
+        assert_eq!(parse_command("history bob"), None);
+        assert_eq!(parse_command("join general"), None);
+        assert_eq!(parse_command("dance"), None);
+    }
 }
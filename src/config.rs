@@ -0,0 +1,338 @@
+use std::{env, fmt, fs, io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::ClientConfig,
+    parser::PeerId,
+    server::{BroadcastLimit, OfflineLimit, OutboundLimit, ServerConfig, SlowConsumerPolicy},
+    shared::Heartbeat,
+    tls::{ClientTls, ServerTls, Trust},
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    // A setting that parsed but makes no sense, e.g. a cert without a key.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// Everything either side can be configured with, as it appears in the TOML file. Missing
+// settings keep their defaults; main.rs layers environment variables and flags on top.
+// Durations are in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LevelFilter,
+    pub heartbeat: HeartbeatSection,
+    pub server: ServerSection,
+    pub client: ClientSection,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSection {
+    // 0 turns heartbeats off.
+    pub interval: f64,
+    pub timeout: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub bind: SocketAddr,
+    pub max_peers: PeerId,
+    pub drain_timeout: f64,
+    // Without a users file anyone may log in under any name.
+    pub users: Option<PathBuf>,
+    pub history: Option<PathBuf>,
+    // Serve TLS with this certificate chain and key.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub broadcast_burst: u32,
+    pub broadcast_interval: f64,
+    // Wait after a failed login from an address before it may try again; 0 disables it.
+    pub login_delay: f64,
+    pub offline_quota: usize,
+    pub offline_expiry: f64,
+    pub outbound_capacity: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSection {
+    pub server: String,
+    // Connect over TLS, trusting either these CAs or exactly this certificate.
+    pub ca: Option<PathBuf>,
+    pub pin: Option<PathBuf>,
+    pub server_name: String,
+    pub read_receipts: bool,
+    // Where each user's end-to-end key is kept, as identity_<username>.key. Defaults to
+    // md-redis under $XDG_DATA_HOME, or ~/.local/share.
+    pub identity_dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: LevelFilter::Info,
+            heartbeat: HeartbeatSection::default(),
+            server: ServerSection::default(),
+            client: ClientSection::default(),
+        }
+    }
+}
+
+impl Default for HeartbeatSection {
+    fn default() -> Self {
+        let heartbeat = Heartbeat::default();
+        HeartbeatSection {
+            interval: heartbeat.interval.as_secs_f64(),
+            timeout: heartbeat.timeout.as_secs_f64(),
+        }
+    }
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        let config = ServerConfig::default();
+        ServerSection {
+            bind: config.bind_addr,
+            max_peers: config.max_peers,
+            drain_timeout: config.drain_timeout.as_secs_f64(),
+            users: None,
+            history: None,
+            cert: None,
+            key: None,
+            broadcast_burst: config.broadcast_limit.burst,
+            broadcast_interval: config.broadcast_limit.interval.as_secs_f64(),
+            login_delay: config.login_delay.as_secs_f64(),
+            offline_quota: config.offline_limit.quota,
+            offline_expiry: config.offline_limit.expiry.as_secs_f64(),
+            outbound_capacity: config.outbound_limit.capacity,
+            slow_consumer: config.outbound_limit.policy,
+        }
+    }
+}
+
+impl Default for ClientSection {
+    fn default() -> Self {
+        let config = ClientConfig::default();
+        ClientSection {
+            server: config.server_addr,
+            ca: None,
+            pin: None,
+            server_name: "localhost".to_string(),
+            read_receipts: config.read_receipts,
+            identity_dir: None,
+        }
+    }
+}
+
+fn seconds(value: f64, what: &'static str) -> Result<Duration, ConfigError> {
+    Duration::try_from_secs_f64(value).map_err(|_| ConfigError::Invalid(what))
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, ConfigError> {
+        Self::parse(&fs::read_to_string(path).map_err(ConfigError::Io)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Parse)
+    }
+
+    // The effective settings, in the same format the file is read in.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Config always serializes")
+    }
+
+    // A timeout no longer than the interval would drop peers before they could answer.
+    pub fn heartbeat(&self) -> Result<Heartbeat, ConfigError> {
+        let heartbeat = Heartbeat {
+            interval: seconds(self.heartbeat.interval, "Invalid heartbeat interval")?,
+            timeout: seconds(self.heartbeat.timeout, "Invalid heartbeat timeout")?,
+        };
+        if heartbeat.is_enabled() && heartbeat.timeout <= heartbeat.interval {
+            return Err(ConfigError::Invalid(
+                "Heartbeat timeout must be longer than the interval",
+            ));
+        }
+        Ok(heartbeat)
+    }
+
+    pub fn server_config(&self) -> Result<ServerConfig, ConfigError> {
+        let server = &self.server;
+        let tls = match (&server.cert, &server.key) {
+            (Some(cert), Some(key)) => Some(ServerTls {
+                cert_path: cert.clone(),
+                key_path: key.clone(),
+            }),
+            (None, None) => None,
+            _ => return Err(ConfigError::Invalid("cert and key must be given together")),
+        };
+        Ok(ServerConfig {
+            bind_addr: server.bind,
+            max_peers: server.max_peers,
+            drain_timeout: seconds(server.drain_timeout, "Invalid drain timeout")?,
+            broadcast_limit: BroadcastLimit {
+                burst: server.broadcast_burst,
+                interval: seconds(server.broadcast_interval, "Invalid broadcast interval")?,
+            },
+            login_delay: seconds(server.login_delay, "Invalid login delay")?,
+            offline_limit: OfflineLimit {
+                quota: server.offline_quota,
+                expiry: seconds(server.offline_expiry, "Invalid offline expiry")?,
+            },
+            outbound_limit: OutboundLimit {
+                capacity: server.outbound_capacity,
+                policy: server.slow_consumer,
+            },
+            heartbeat: self.heartbeat()?,
+            history_path: server.history.clone(),
+            tls,
+        })
+    }
+
+    pub fn client_config(&self) -> Result<ClientConfig, ConfigError> {
+        let client = &self.client;
+        let trust = match (&client.ca, &client.pin) {
+            (Some(ca), None) => Some(Trust::CaBundle(ca.clone())),
+            (None, Some(pin)) => Some(Trust::Pinned(pin.clone())),
+            (None, None) => None,
+            _ => return Err(ConfigError::Invalid("Use either ca or pin, not both")),
+        };
+        Ok(ClientConfig {
+            server_addr: client.server.clone(),
+            tls: trust.map(|trust| ClientTls {
+                trust,
+                server_name: client.server_name.clone(),
+            }),
+            read_receipts: client.read_receipts,
+            heartbeat: self.heartbeat()?,
+            identity: None,
+        })
+    }
+
+    // The key file for `username`, which may not exist yet.
+    pub fn identity_path(&self, username: &str) -> Result<PathBuf, ConfigError> {
+        let dir = match &self.client.identity_dir {
+            Some(dir) => dir.clone(),
+            None => default_data_dir()
+                .ok_or(ConfigError::Invalid("No home directory, set identity_dir"))?
+                .join("md-redis"),
+        };
+        Ok(dir.join(format!("identity_{}.key", username)))
+    }
+}
+
+fn default_data_dir() -> Option<PathBuf> {
+    let nonempty = |var| env::var_os(var).filter(|value| !value.is_empty());
+    match nonempty("XDG_DATA_HOME") {
+        Some(dir) => Some(dir.into()),
+        None => nonempty("HOME").map(|home| PathBuf::from(home).join(".local/share")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_overrides_defaults() {
+        let config = Config::parse(
+            r#"
+            log_level = "debug"
+
+            [heartbeat]
+            interval = 0
+
+            [server]
+            bind = "127.0.0.1:9000"
+            slow_consumer = "disconnect"
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [client]
+            server = "chat.example.com:9000"
+            pin = "server.der"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.log_level, LevelFilter::Debug);
+
+        let server = config.server_config().unwrap();
+        assert_eq!(server.bind_addr, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(server.outbound_limit.policy, SlowConsumerPolicy::Disconnect);
+        assert!(!server.heartbeat.is_enabled());
+        assert!(server.tls.is_some());
+        // Untouched settings keep their defaults
+        assert_eq!(server.max_peers, ServerConfig::default().max_peers);
+
+        let client = config.client_config().unwrap();
+        assert_eq!(client.server_addr, "chat.example.com:9000");
+        assert!(matches!(
+            client.tls,
+            Some(ClientTls {
+                trust: Trust::Pinned(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_bad_settings() {
+        assert!(matches!(
+            Config::parse("[server]\nbnid = \"0.0.0.0:1\""),
+            Err(ConfigError::Parse(_))
+        ));
+        let config = Config::parse("[server]\ncert = \"cert.pem\"").unwrap();
+        assert!(matches!(
+            config.server_config(),
+            Err(ConfigError::Invalid(_))
+        ));
+        let config = Config::parse("[heartbeat]\ntimeout = -1").unwrap();
+        assert!(matches!(
+            config.client_config(),
+            Err(ConfigError::Invalid(_))
+        ));
+        let config = Config::parse("[heartbeat]\ninterval = 30\ntimeout = 10").unwrap();
+        assert!(matches!(
+            config.server_config(),
+            Err(ConfigError::Invalid(_))
+        ));
+        // Without heartbeats the timeout doesn't matter
+        let config = Config::parse("[heartbeat]\ninterval = 0\ntimeout = 0").unwrap();
+        assert!(config.server_config().is_ok());
+    }
+
+    #[test]
+    fn test_identity_path() {
+        let config = Config::parse("[client]\nidentity_dir = \"keys\"").unwrap();
+        assert_eq!(
+            config.identity_path("bob").unwrap(),
+            PathBuf::from("keys/identity_bob.key")
+        );
+    }
+
+    #[test]
+    fn test_printed_config_reads_back() {
+        let mut config = Config::default();
+        config.server.history = Some("history.log".into());
+        config.server.slow_consumer = SlowConsumerPolicy::DropNewest;
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{io, thread};
+
+use md_redis::{
+    Client, ConnectionState, Event,
+    client::parse_command,
+    parser::{BROADCAST_PEER, PeerId, is_room},
+};
+
+// What the next line, or pair of lines, of input asks for.
+#[derive(Debug, PartialEq)]
+enum Input {
+    Command(String),
+    Send(PeerId, String),
+    // The message meant for them has been read and dropped.
+    UnknownPeer(String),
+}
+
+// Reads alternating peer and message lines until they end. A peer is given by id, by
+// username or as "*" for everyone; a line starting with '/' is a command instead.
+fn next_input(
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+    resolve: impl Fn(&str) -> Option<PeerId>,
+) -> Option<Input> {
+    let line = lines.next()?.ok()?;
+    if let Some(command) = line.trim().strip_prefix('/') {
+        return Some(Input::Command(command.to_string()));
+    }
+    let peer = resolve(line.trim());
+    // Read the message either way, so it isn't taken for the next peer
+    let msg = lines.next()?.ok()?;
+    match peer {
+        Some(peer) => Some(Input::Send(peer, msg.trim().to_string())),
+        None => Some(Input::UnknownPeer(line.trim().to_string())),
+    }
+}
+
+pub fn input_loop(client: &Client) {
+    let mut lines = io::stdin().lines();
+    while let Some(input) = next_input(&mut lines, |peer| client.resolve_peer(peer)) {
+        match input {
+            Input::Command(command) => match parse_command(&command) {
+                Some(sys_msg) => {
+                    log::info!("Sending command: {:?}", sys_msg);
+                    if let Err(e) = client.send_system(sys_msg) {
+                        log::warn!("{}, command dropped", e);
+                    }
+                }
+                None => log::warn!("Unknown command: /{}", command),
+            },
+            Input::Send(peer, msg) => {
+                log::info!("Sending to {}: {}", peer, msg);
+                if let Err(e) = client.send(peer, msg.as_bytes()) {
+                    log::warn!("Could not send to {}: {}", peer, e);
+                }
+            }
+            Input::UnknownPeer(peer) => log::warn!("Unknown peer: {}, message dropped", peer),
+        }
+    }
+}
+
+// The client without a window: input from stdin, everything that arrives on stdout.
+pub fn run(client: Client) {
+    println!("You: {}", client.fingerprint());
+    thread::scope(|s| {
+        s.spawn(|| {
+            while let Some(event) = client.recv() {
+                println!("{}", describe(&event));
+                if let Event::Message(msg) = &event {
+                    let _ = client.mark_read(msg.peer);
+                }
+            }
+        });
+        input_loop(&client);
+        client.close();
+    });
+}
+
+pub fn describe(event: &Event) -> String {
+    match event {
+        Event::Connection(ConnectionState::Connecting) => "Connecting...".to_string(),
+        Event::Connection(ConnectionState::Connected) => "Connected".to_string(),
+        Event::Connection(ConnectionState::RetryingIn(delay)) => {
+            format!("Disconnected, retrying in {:.1}s", delay.as_secs_f32())
+        }
+        Event::Peers(peers) => {
+            let peers = peers
+                .iter()
+                .map(|(peer, name)| format!("{} {}", peer, name))
+                .collect::<Vec<_>>();
+            format!("Peers: {}", peers.join(", "))
+        }
+        Event::Message(msg) => {
+            let content = String::from_utf8_lossy(&msg.content);
+            if msg.peer == BROADCAST_PEER {
+                format!("[* {}] {}", msg.sender(), content)
+            } else if is_room(msg.peer) {
+                format!("[{} {}] {}", msg.peer, msg.sender(), content)
+            } else {
+                format!("[{}] {}", msg.peer, content)
+            }
+        }
+        Event::Status { peer, id, status } => {
+            format!("Message {} to {}: {:?}", id, peer, status)
+        }
+        Event::System(sys_msg) => format!("{:?}", sys_msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_peer_drops_its_message() {
+        let input = "nobody\nhello /list\nbob\n hi \n/list\n";
+        let mut lines = input.lines().map(|line| Ok(line.to_string()));
+        let resolve = |name: &str| (name == "bob").then_some(2);
+        assert_eq!(
+            next_input(&mut lines, resolve),
+            Some(Input::UnknownPeer("nobody".to_string()))
+        );
+        assert_eq!(
+            next_input(&mut lines, resolve),
+            Some(Input::Send(2, "hi".to_string()))
+        );
+        assert_eq!(
+            next_input(&mut lines, resolve),
+            Some(Input::Command("list".to_string()))
+        );
+        assert_eq!(next_input(&mut lines, resolve), None);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    parser::IdentityKey,
+    shared::{from_hex, to_hex},
+};
+
+// Sealed payloads start with this. 0xFF never occurs in UTF-8, so it can't be mistaken for text.
+const SEALED_MAGIC: [u8; 4] = [0xFF, b'E', b'2', b'E'];
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, PartialEq)]
+pub enum E2eError {
+    NotSealed,
+    // The peer's key can't be used, e.g. a low-order point.
+    BadKey,
+    EncryptFailed,
+    // Tampered with, or sealed for someone else.
+    DecryptFailed,
+}
+
+// A client's long-term X25519 key pair. Direct messages are sealed with a key derived from
+// both parties' identity keys, so only the two of them can read them; the server just relays.
+pub struct Identity {
+    secret: StaticSecret,
+    public: IdentityKey,
+}
+
+impl Identity {
+    pub fn generate() -> io::Result<Self> {
+        let mut secret = [0; 32];
+        getrandom::fill(&mut secret)?;
+        Ok(Self::from_secret(secret))
+    }
+
+    fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret).to_bytes();
+        Identity { secret, public }
+    }
+
+    // Keeps the same key, and so the same fingerprint, across runs.
+    pub fn load_or_create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let secret = from_hex(text.trim())
+                    .and_then(|secret| secret.try_into().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Malformed identity key file")
+                    })?;
+                Ok(Self::from_secret(secret))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate()?;
+                // Readable by its owner only from the start, and never over a file that
+                // appeared meanwhile
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                options
+                    .open(path)?
+                    .write_all(to_hex(identity.secret.as_bytes()).as_bytes())?;
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn public_key(&self) -> IdentityKey {
+        self.public
+    }
+
+    // Each direction between two parties has its own key, so a message can't be handed back
+    // to its sender as if the other side had written it.
+    fn direction_key(
+        &self,
+        their_key: &IdentityKey,
+        outgoing: bool,
+    ) -> Result<XChaCha20Poly1305, E2eError> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*their_key));
+        if !shared.was_contributory() {
+            return Err(E2eError::BadKey);
+        }
+        let (from, to) = if outgoing {
+            (&self.public, their_key)
+        } else {
+            (their_key, &self.public)
+        };
+        let mut info = b"md-redis e2e v2".to_vec();
+        info.extend_from_slice(from);
+        info.extend_from_slice(to);
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .map_err(|_| E2eError::BadKey)?;
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    pub fn seal(&self, their_key: &IdentityKey, plaintext: &[u8]) -> Result<Vec<u8>, E2eError> {
+        let cipher = self.direction_key(their_key, true)?;
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::fill(&mut nonce).map_err(|_| E2eError::EncryptFailed)?;
+        let payload = Payload {
+            msg: plaintext,
+            aad: &SEALED_MAGIC,
+        };
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| E2eError::EncryptFailed)?;
+        let mut sealed = SEALED_MAGIC.to_vec();
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // Opens what the owner of `their_key` sealed for us.
+    pub fn open(&self, their_key: &IdentityKey, sealed: &[u8]) -> Result<Vec<u8>, E2eError> {
+        unseal(&self.direction_key(their_key, false)?, sealed)
+    }
+
+    // Opens what we sealed for the owner of `their_key`, e.g. when it comes back in history.
+    pub fn open_sent(&self, their_key: &IdentityKey, sealed: &[u8]) -> Result<Vec<u8>, E2eError> {
+        unseal(&self.direction_key(their_key, true)?, sealed)
+    }
+}
+
+fn unseal(cipher: &XChaCha20Poly1305, sealed: &[u8]) -> Result<Vec<u8>, E2eError> {
+    let body = sealed
+        .strip_prefix(&SEALED_MAGIC)
+        .ok_or(E2eError::NotSealed)?;
+    if body.len() < NONCE_LEN {
+        return Err(E2eError::DecryptFailed);
+    }
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &SEALED_MAGIC,
+    };
+    cipher
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| E2eError::DecryptFailed)
+}
+
+pub fn is_sealed(content: &[u8]) -> bool {
+    content.starts_with(&SEALED_MAGIC)
+}
+
+// Short, readable digest of a key for users to compare out of band, e.g. "3f2a 91c0 ...".
+pub fn fingerprint(key: &IdentityKey) -> String {
+    let digest = Sha256::digest(key);
+    digest[..16]
+        .chunks(2)
+        .map(to_hex)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let alice = Identity::generate().unwrap();
+        let bob = Identity::generate().unwrap();
+        let sealed = alice.seal(&bob.public_key(), b"meet at noon").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(12).any(|w| w == b"meet at noon"));
+        assert_eq!(
+            bob.open(&alice.public_key(), &sealed).unwrap(),
+            b"meet at noon"
+        );
+
+        // Nobody else can read it, and tampering is caught
+        let eve = Identity::generate().unwrap();
+        assert_eq!(
+            eve.open(&alice.public_key(), &sealed),
+            Err(E2eError::DecryptFailed)
+        );
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            bob.open(&alice.public_key(), &tampered),
+            Err(E2eError::DecryptFailed)
+        );
+        assert_eq!(
+            bob.open(&alice.public_key(), b"hello"),
+            Err(E2eError::NotSealed)
+        );
+
+        // Only its sender can read it back, and not as if it came from the other side
+        assert_eq!(
+            alice.open_sent(&bob.public_key(), &sealed).unwrap(),
+            b"meet at noon"
+        );
+        assert_eq!(
+            alice.open(&bob.public_key(), &sealed),
+            Err(E2eError::DecryptFailed)
+        );
+    }
+
+    #[test]
+    fn test_rejects_low_order_keys() {
+        let alice = Identity::generate().unwrap();
+        assert_eq!(alice.seal(&[0; 32], b"hi"), Err(E2eError::BadKey));
+    }
+
+    #[test]
+    fn test_identity_persists() {
+        let path = std::env::temp_dir().join(format!("md-redis-identity-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let first = Identity::load_or_create(&path).unwrap();
+        let second = Identity::load_or_create(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(first.public_key(), second.public_key());
+        assert_eq!(
+            fingerprint(&first.public_key()),
+            fingerprint(&second.public_key())
+        );
+        assert_eq!(fingerprint(&first.public_key()).len(), 39);
+    }
+}
@@ -0,0 +1,269 @@
+use md_redis::{Client, ConnectionState, Event};
+use raylib::prelude::*;
+
+use crate::chat::{ChatView, wrap};
+
+const FONT: i32 = 20;
+const SMALL_FONT: i32 = 10;
+const PAD: i32 = 8;
+const LINE: i32 = FONT + 4;
+const TOP_BAR: i32 = 40;
+const SIDEBAR: i32 = 220;
+const INPUT: i32 = FONT + 2 * PAD;
+const NOTICE: i32 = FONT;
+
+// Where everything goes in a window of the given size.
+struct Layout {
+    width: i32,
+    height: i32,
+}
+
+impl Layout {
+    fn pane_x(&self) -> i32 {
+        SIDEBAR + PAD
+    }
+
+    fn pane_width(&self) -> i32 {
+        self.width - SIDEBAR - 2 * PAD
+    }
+
+    fn input_y(&self) -> i32 {
+        self.height - INPUT - PAD
+    }
+
+    // The conversation sits between the top bar and the notice line over the input box.
+    fn pane_height(&self) -> i32 {
+        self.input_y() - NOTICE - PAD - TOP_BAR
+    }
+
+    fn visible_lines(&self) -> usize {
+        (self.pane_height() / LINE).max(0) as usize
+    }
+
+    // The sidebar entry at this point, if any.
+    fn entry_at(&self, point: Vector2) -> Option<usize> {
+        let (x, y) = (point.x as i32, point.y as i32);
+        if x >= SIDEBAR || y < TOP_BAR {
+            return None;
+        }
+        Some(((y - TOP_BAR) / LINE) as usize)
+    }
+}
+
+// The chat window: conversations on the left, the open one on the right and a line to
+// type in at the bottom. Enter sends, and a line starting with '/' is a command; Up and
+// Down switch conversations, the mouse wheel and Page Up/Down scroll.
+pub fn run(client: Client) {
+    let (mut rl, thread) = raylib::init()
+        .size(800, 600)
+        .resizable()
+        .title("md-redis")
+        .build();
+    rl.set_target_fps(60);
+    // Escape is for editing, not for quitting
+    rl.set_exit_key(None);
+
+    let fingerprint = client.fingerprint();
+    let mut view = ChatView::new();
+    while !rl.window_should_close() {
+        while let Some(event) = client.try_recv() {
+            if let Event::Peers(peers) = &event {
+                log::info!("Updated peers: {:?}", peers);
+            }
+            view.handle(&event);
+        }
+        let layout = Layout {
+            width: rl.get_screen_width(),
+            height: rl.get_screen_height(),
+        };
+        handle_input(&mut rl, &layout, &mut view, &client);
+
+        let lines = view
+            .conversation(&client)
+            .iter()
+            .flat_map(|line| {
+                wrap(line, layout.pane_width(), |text| {
+                    rl.measure_text(text, FONT)
+                })
+            })
+            .collect::<Vec<_>>();
+        let lines = &lines[view.visible(lines.len(), layout.visible_lines())];
+        let cursor_on = (rl.get_time() * 2.0) as i64 % 2 == 0;
+        view.mark_read(&client);
+
+        let mut d = rl.begin_drawing(&thread);
+        d.clear_background(Color::RAYWHITE);
+        draw_top_bar(&mut d, &layout, &view, &fingerprint);
+        draw_sidebar(&mut d, &layout, &view, &client);
+        draw_conversation(&mut d, &layout, &view, lines);
+        draw_input(&mut d, &layout, &view, cursor_on);
+    }
+
+    client.close();
+}
+
+fn pressed(rl: &RaylibHandle, key: KeyboardKey) -> bool {
+    rl.is_key_pressed(key) || rl.is_key_pressed_repeat(key)
+}
+
+fn handle_input(rl: &mut RaylibHandle, layout: &Layout, view: &mut ChatView, client: &Client) {
+    while let Some(c) = rl.get_char_pressed() {
+        view.input.insert(c);
+    }
+    if pressed(rl, KeyboardKey::KEY_BACKSPACE) {
+        view.input.backspace();
+    }
+    if pressed(rl, KeyboardKey::KEY_DELETE) {
+        view.input.delete();
+    }
+    if pressed(rl, KeyboardKey::KEY_LEFT) {
+        view.input.left();
+    }
+    if pressed(rl, KeyboardKey::KEY_RIGHT) {
+        view.input.right();
+    }
+    if rl.is_key_pressed(KeyboardKey::KEY_HOME) {
+        view.input.home();
+    }
+    if rl.is_key_pressed(KeyboardKey::KEY_END) {
+        view.input.end();
+    }
+    if rl.is_key_pressed(KeyboardKey::KEY_ENTER) || rl.is_key_pressed(KeyboardKey::KEY_KP_ENTER) {
+        view.submit(client);
+    }
+    if pressed(rl, KeyboardKey::KEY_UP) {
+        view.select_next(-1);
+    }
+    if pressed(rl, KeyboardKey::KEY_DOWN) {
+        view.select_next(1);
+    }
+    let page = layout.visible_lines().max(1);
+    if pressed(rl, KeyboardKey::KEY_PAGE_UP) {
+        view.scroll += page;
+    }
+    if pressed(rl, KeyboardKey::KEY_PAGE_DOWN) {
+        view.scroll = view.scroll.saturating_sub(page);
+    }
+
+    let mouse = rl.get_mouse_position();
+    if rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT)
+        && let Some(i) = layout.entry_at(mouse)
+        && let Some(peer) = view.entries().get(i)
+    {
+        view.select(*peer);
+    }
+    let wheel = rl.get_mouse_wheel_move();
+    if wheel != 0.0 && mouse.x as i32 >= SIDEBAR {
+        let lines = (wheel * 3.0) as isize;
+        view.scroll = view.scroll.saturating_add_signed(lines);
+    }
+}
+
+fn draw_top_bar(d: &mut RaylibDrawHandle, layout: &Layout, view: &ChatView, fingerprint: &str) {
+    d.draw_rectangle(0, 0, layout.width, TOP_BAR, Color::LIGHTGRAY);
+    let (status, color) = match view.connection {
+        ConnectionState::Connecting => ("Connecting...".to_string(), Color::DARKGRAY),
+        ConnectionState::Connected => ("Connected".to_string(), Color::DARKGREEN),
+        ConnectionState::RetryingIn(delay) => (
+            format!("Disconnected, retrying in {:.1}s", delay.as_secs_f32()),
+            Color::RED,
+        ),
+    };
+    d.draw_text(&status, PAD, PAD, FONT, color);
+    d.draw_text(
+        &format!("Your key: {}", fingerprint),
+        PAD,
+        TOP_BAR - SMALL_FONT - 2,
+        SMALL_FONT,
+        Color::DARKGRAY,
+    );
+}
+
+fn draw_sidebar(d: &mut RaylibDrawHandle, layout: &Layout, view: &ChatView, client: &Client) {
+    d.draw_rectangle(
+        0,
+        TOP_BAR,
+        SIDEBAR,
+        layout.height - TOP_BAR,
+        Color::LIGHTGRAY,
+    );
+    for (i, peer) in view.entries().into_iter().enumerate() {
+        let y = TOP_BAR + i as i32 * LINE;
+        if view.selected == Some(peer) {
+            d.draw_rectangle(0, y, SIDEBAR, LINE, Color::SKYBLUE);
+        }
+        let mut label = view.name(peer);
+        let mut color = Color::BLACK;
+        if !view.is_online(peer) {
+            label.push_str(" (offline)");
+            color = Color::GRAY;
+        }
+        let failed = client.failed(peer);
+        if failed > 0 {
+            label.push_str(&format!(" !{}", failed));
+        }
+        d.draw_text(&label, PAD, y + 2, FONT, color);
+        let unread = view.unread(peer);
+        if unread > 0 {
+            let count = unread.to_string();
+            let width = d.measure_text(&count, FONT) + PAD;
+            let x = SIDEBAR - width - PAD;
+            d.draw_rectangle(x, y + 1, width, LINE - 2, Color::RED);
+            d.draw_text(&count, x + PAD / 2, y + 2, FONT, Color::WHITE);
+        }
+    }
+}
+
+// The lines in view, newest at the bottom.
+fn draw_conversation(d: &mut RaylibDrawHandle, layout: &Layout, view: &ChatView, lines: &[String]) {
+    if view.selected.is_none() {
+        d.draw_text(
+            "Pick someone on the left to chat with",
+            layout.pane_x(),
+            TOP_BAR + PAD,
+            FONT,
+            Color::GRAY,
+        );
+        return;
+    }
+    let bottom = TOP_BAR + layout.pane_height();
+    for (i, line) in lines.iter().rev().enumerate() {
+        let y = bottom - (i as i32 + 1) * LINE;
+        let color = if line.starts_with("You: ") {
+            Color::DARKBLUE
+        } else {
+            Color::BLACK
+        };
+        d.draw_text(line, layout.pane_x(), y, FONT, color);
+    }
+    if view.scroll > 0 {
+        let more = format!("{} more below", view.scroll);
+        d.draw_text(&more, layout.pane_x(), bottom, SMALL_FONT, Color::GRAY);
+    }
+}
+
+fn draw_input(d: &mut RaylibDrawHandle, layout: &Layout, view: &ChatView, cursor_on: bool) {
+    let y = layout.input_y();
+    if let Some(notice) = &view.notice {
+        d.draw_text(notice, layout.pane_x(), y - NOTICE, FONT - 4, Color::MAROON);
+    }
+    let (x, width) = (layout.pane_x(), layout.pane_width());
+    d.draw_rectangle(x, y, width, INPUT, Color::WHITE);
+    d.draw_rectangle_lines(x, y, width, INPUT, Color::DARKGRAY);
+
+    // Keep the cursor in view when the line is wider than the box
+    let cursor_x = d.measure_text(view.input.before_cursor(), FONT);
+    let offset = (cursor_x - (width - 2 * PAD)).max(0);
+    let mut s = d.begin_scissor_mode(x + 1, y + 1, width - 2, INPUT - 2);
+    s.draw_text(
+        view.input.text(),
+        x + PAD - offset,
+        y + PAD,
+        FONT,
+        Color::BLACK,
+    );
+    if cursor_on {
+        let cursor_x = x + PAD + cursor_x - offset;
+        s.draw_line(cursor_x, y + PAD, cursor_x, y + PAD + FONT, Color::BLACK);
+    }
+}
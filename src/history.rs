@@ -0,0 +1,386 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    parser::{BROADCAST_PEER, PeerId},
+    shared::{from_hex, to_hex},
+};
+
+// Most records returned by a single query, whatever the client asks for.
+pub const MAX_PAGE: usize = 100;
+// Most messages kept without a file, the oldest going first.
+pub const MAX_RESIDENT: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub id: u64,
+    // Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub src: PeerId,
+    pub dst: PeerId,
+    pub content: Vec<u8>,
+}
+
+// Enough of a message to tell whether a query wants it.
+struct Entry {
+    id: u64,
+    src: PeerId,
+    dst: PeerId,
+    body: Body,
+}
+
+enum Body {
+    // Where its line is in the file; read back only once a query wants it.
+    OnDisk { offset: u64, len: usize },
+    // Without a file, or if writing to it failed, there is nowhere else to keep it.
+    Resident { timestamp: u64, content: Vec<u8> },
+}
+
+// Direct, room and broadcast messages the server routed, plus which id each username is
+// bound to, since recorded ids mean nothing without that. With a file, everything is
+// appended to it as it happens so it all comes back after a restart, and only an index
+// stays in memory:
+//   "U:id:name" binds a username to an id
+//   "M:id:timestamp:src:dst:content" records a message, content in hex
+pub struct History {
+    index: VecDeque<Entry>,
+    users: HashMap<String, PeerId>,
+    file: Option<File>,
+    // Where the next line goes in the file.
+    file_len: u64,
+    next_id: u64,
+}
+
+impl History {
+    pub fn in_memory() -> Self {
+        History {
+            index: VecDeque::new(),
+            users: HashMap::new(),
+            file: None,
+            file_len: 0,
+            next_id: 1,
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut history = Self::in_memory();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        let mut number = 0;
+        let mut ended = true;
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line)?;
+            if len == 0 {
+                break;
+            }
+            number += 1;
+            ended = line.ends_with('\n');
+            // A crash mid-write leaves a partial last line; skip it rather than refuse to start.
+            if !history.load_line(line.trim_end_matches('\n'), history.file_len) {
+                eprintln!("Skipping malformed history line {}", number);
+            }
+            history.file_len += len as u64;
+        }
+        // Don't let the next line run on from a partial one.
+        if !ended {
+            writeln!(file)?;
+            history.file_len += 1;
+        }
+        history.file = Some(file);
+        Ok(history)
+    }
+
+    fn load_line(&mut self, line: &str, offset: u64) -> bool {
+        let fields = line.split(':').collect::<Vec<&str>>();
+        match fields[..] {
+            ["U", id, name] => {
+                let Ok(id) = id.parse() else {
+                    return false;
+                };
+                self.users.insert(name.to_string(), id);
+            }
+            ["M", ..] => {
+                let Some(record) = parse_record(line) else {
+                    return false;
+                };
+                self.next_id = self.next_id.max(record.id + 1);
+                self.index.push_back(Entry {
+                    id: record.id,
+                    src: record.src,
+                    dst: record.dst,
+                    body: Body::OnDisk {
+                        offset,
+                        len: line.len(),
+                    },
+                });
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Returns where the line starts in the file, or None if there is no file or the write
+    // failed; memory then has to do, and only the next restart loses out.
+    fn append(&mut self, line: String) -> Option<u64> {
+        let file = self.file.as_mut()?;
+        let offset = self.file_len;
+        match writeln!(file, "{}", line) {
+            Ok(()) => {
+                self.file_len += line.len() as u64 + 1;
+                Some(offset)
+            }
+            Err(e) => {
+                eprintln!("Failed to write history: {}", e);
+                // Part of it may have made it
+                self.file_len = file.metadata().map_or(self.file_len, |meta| meta.len());
+                None
+            }
+        }
+    }
+
+    // Usernames and the ids they were bound to in earlier runs.
+    pub fn users(&self) -> &HashMap<String, PeerId> {
+        &self.users
+    }
+
+    pub fn bind_user(&mut self, name: &str, id: PeerId) {
+        self.users.insert(name.to_string(), id);
+        self.append(format!("U:{}:{}", id, name));
+    }
+
+    // Returns the id given to the message.
+    pub fn record(&mut self, src: PeerId, dst: PeerId, content: &[u8]) -> u64 {
+        let id = self.next_id;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.next_id += 1;
+        let line = format!("M:{}:{}:{}:{}:{}", id, timestamp, src, dst, to_hex(content));
+        let len = line.len();
+        let body = match self.append(line) {
+            Some(offset) => Body::OnDisk { offset, len },
+            None => Body::Resident {
+                timestamp,
+                content: content.to_vec(),
+            },
+        };
+        if self.file.is_none() && self.index.len() == MAX_RESIDENT {
+            self.index.pop_front();
+        }
+        self.index.push_back(Entry { id, src, dst, body });
+        id
+    }
+
+    // The id the next message will get.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    // The last `limit` messages between `me` and `peer` (or all broadcasts, for
+    // BROADCAST_PEER) with an id below `before`, oldest first.
+    pub fn query(
+        &self,
+        me: PeerId,
+        peer: PeerId,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<Record> {
+        self.page(before, limit, |entry| {
+            if peer == BROADCAST_PEER {
+                entry.dst == BROADCAST_PEER
+            } else {
+                (entry.src == me && entry.dst == peer) || (entry.src == peer && entry.dst == me)
+            }
+        })
+    }
+
+    // Like query, for the messages sent to `room` with an id of at least `since`. Room ids
+    // are handed out again once a room is gone, so `since` keeps an earlier room's out.
+    pub fn query_room(
+        &self,
+        room: PeerId,
+        since: u64,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<Record> {
+        self.page(before, limit, |entry| {
+            entry.dst == room && entry.id >= since
+        })
+    }
+
+    fn page(
+        &self,
+        before: Option<u64>,
+        limit: usize,
+        matches: impl Fn(&Entry) -> bool,
+    ) -> Vec<Record> {
+        let mut page = self
+            .index
+            .iter()
+            .rev()
+            .filter(|entry| before.is_none_or(|before| entry.id < before))
+            .filter(|entry| matches(entry))
+            .take(limit.min(MAX_PAGE))
+            .filter_map(|entry| self.load(entry))
+            .collect::<Vec<Record>>();
+        page.reverse();
+        page
+    }
+
+    fn load(&self, entry: &Entry) -> Option<Record> {
+        let (offset, len) = match &entry.body {
+            Body::Resident { timestamp, content } => {
+                return Some(Record {
+                    id: entry.id,
+                    timestamp: *timestamp,
+                    src: entry.src,
+                    dst: entry.dst,
+                    content: content.clone(),
+                });
+            }
+            Body::OnDisk { offset, len } => (*offset, *len),
+        };
+        let mut file = self.file.as_ref()?;
+        let mut line = vec![0; len];
+        let read = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut line));
+        if let Err(e) = read {
+            eprintln!("Failed to read history #{}: {}", entry.id, e);
+            return None;
+        }
+        let record = String::from_utf8(line)
+            .ok()
+            .and_then(|line| parse_record(&line));
+        if record.as_ref().is_none_or(|record| record.id != entry.id) {
+            eprintln!("History #{} has changed on disk", entry.id);
+            return None;
+        }
+        record
+    }
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let fields = line.split(':').collect::<Vec<&str>>();
+    let ["M", id, timestamp, src, dst, content] = fields[..] else {
+        return None;
+    };
+    Some(Record {
+        id: id.parse().ok()?,
+        timestamp: timestamp.parse().ok()?,
+        src: src.parse().ok()?,
+        dst: dst.parse().ok()?,
+        content: from_hex(content)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_query_pages_backwards() {
+        let mut history = History::in_memory();
+        for i in 0..5u8 {
+            history.record(1, 2, &[i]);
+            history.record(2, 1, &[i + 10]);
+            history.record(3, 1, b"other");
+        }
+        history.record(1, BROADCAST_PEER, b"all");
+
+        let page = history.query(1, 2, None, 3);
+        let contents = page.iter().map(|r| r.content[0]).collect::<Vec<u8>>();
+        assert_eq!(contents, vec![13, 4, 14]);
+        let page = history.query(2, 1, Some(page[0].id), 3);
+        let contents = page.iter().map(|r| r.content[0]).collect::<Vec<u8>>();
+        assert_eq!(contents, vec![2, 12, 3]);
+
+        assert_eq!(history.query(1, BROADCAST_PEER, None, 10).len(), 1);
+        assert_eq!(history.query(1, 2, None, 1000).len(), 10);
+        assert!(history.query(4, 2, None, 10).is_empty());
+    }
+
+    #[test]
+    fn test_room_query_starts_with_the_room() {
+        let room = crate::parser::ROOM_BIT | 1;
+        let mut history = History::in_memory();
+        history.record(1, room, b"earlier room");
+        let since = history.next_id();
+        history.record(1, room, b"hello");
+        history.record(1, 2, b"direct");
+        history.record(2, room, b"hi");
+
+        let page = history.query_room(room, since, None, 10);
+        let contents = page
+            .iter()
+            .map(|r| r.content.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, vec![b"hello".as_slice(), b"hi"]);
+        assert_eq!(history.query_room(room, 1, None, 10).len(), 3);
+        assert_eq!(
+            history.query_room(room, since, Some(page[1].id), 10),
+            vec![page[0].clone()]
+        );
+    }
+
+    #[test]
+    fn test_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("md-redis-history-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut history = History::open(&path).unwrap();
+        history.bind_user("alice", 1);
+        history.bind_user("bob", 2);
+        let first = history.record(1, 2, &[0xFF, b':', b'\n']);
+        drop(history);
+
+        // A torn write at the end is skipped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "M:2:0:1").unwrap();
+        drop(file);
+        History::open(&path).unwrap().bind_user("carol", 3);
+
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.users().get("carol"), Some(&3));
+        assert_eq!(history.users().get("bob"), Some(&2));
+        // Only the index is in memory; contents come from the file
+        assert!(
+            history
+                .index
+                .iter()
+                .all(|entry| matches!(entry.body, Body::OnDisk { .. }))
+        );
+        let page = history.query(2, 1, None, 10);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].content, vec![0xFF, b':', b'\n']);
+        assert_eq!(history.record(2, 1, b"hi"), first + 1);
+        let page = history.query(2, 1, None, 10);
+        assert_eq!(page[1].content, b"hi");
+        drop(history);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_only_keeps_the_latest() {
+        let mut history = History::in_memory();
+        for i in 0..MAX_RESIDENT as u64 + 5 {
+            history.record(1, 2, &i.to_be_bytes());
+        }
+        assert_eq!(history.index.len(), MAX_RESIDENT);
+        let page = history.query(1, 2, Some(7), 10);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].content, 5u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,14 @@
+pub mod async_client;
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod e2e;
+pub mod history;
+pub mod parser;
+pub mod server;
+pub mod shared;
+pub mod tls;
+
+pub use async_client::AsyncClient;
+pub use client::{Client, ClientConfig, ConnectionState, Event, SendError, Status};
+pub use server::{Server, ServerConfig, ShutdownHandle};
@@ -1,21 +1,479 @@
-mod parser;
-mod server;
-mod shared;
-mod client;
+#[cfg(any(feature = "gui", feature = "tui"))]
+mod chat;
+mod console;
+#[cfg(feature = "gui")]
+mod gui;
+mod script;
+#[cfg(feature = "tui")]
+mod tui;
 
-use std::env;
+use std::{
+    env,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, IsTerminal, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+};
+
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use md_redis::{
+    Client, Server, auth::FileAuthenticator, config::Config, parser::is_valid_username,
+    server::SlowConsumerPolicy,
+};
+use simplelog::{ColorChoice, SimpleLogger, TermLogger, TerminalMode, WriteLogger};
+
+// Settings are layered: defaults, then the config file, then environment variables, then
+// flags. Every flag that has a setting in the file can also be set through MD_REDIS_*.
+#[derive(Parser)]
+#[command(version, about = "A small chat server and client")]
+struct Cli {
+    #[arg(
+        long,
+        short,
+        global = true,
+        env = "MD_REDIS_CONFIG",
+        help = "TOML config file"
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        help = "Print the effective config as TOML and exit"
+    )]
+    print_config: bool,
+    #[arg(long, global = true, env = "MD_REDIS_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+    #[arg(
+        long,
+        global = true,
+        env = "MD_REDIS_PING_INTERVAL",
+        help = "Seconds, 0 disables heartbeats"
+    )]
+    ping_interval: Option<f64>,
+    #[arg(long, global = true, env = "MD_REDIS_PING_TIMEOUT", help = "Seconds")]
+    ping_timeout: Option<f64>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run the server (the default)")]
+    Server(ServerArgs),
+    #[command(about = "Connect to a server and chat")]
+    Client(ClientArgs),
+    #[command(about = "Send one message and exit once the server has it")]
+    Send {
+        #[command(flatten)]
+        login: LoginArgs,
+        #[arg(long, help = "Peer id, username, room id or * for everyone")]
+        to: String,
+        text: String,
+    },
+    #[command(about = "Print every message that arrives")]
+    Listen {
+        #[command(flatten)]
+        login: LoginArgs,
+        #[arg(
+            long,
+            help = "One JSON object per message, with non-text content in base64"
+        )]
+        json: bool,
+    },
+    #[command(about = "Send stdin to a peer and print what they send, like netcat")]
+    Pipe {
+        #[command(flatten)]
+        login: LoginArgs,
+        #[arg(long, help = "Peer id, username, room id or * for everyone")]
+        to: String,
+    },
+    #[command(
+        about = "Add a user to a users file, reading the password from the terminal or stdin"
+    )]
+    Adduser {
+        users_file: PathBuf,
+        username: String,
+    },
+}
+
+#[derive(Args, Default)]
+struct ServerArgs {
+    #[arg(long, env = "MD_REDIS_BIND")]
+    bind: Option<SocketAddr>,
+    #[arg(
+        long,
+        env = "MD_REDIS_USERS",
+        help = "Users file; without one anyone may log in"
+    )]
+    users: Option<PathBuf>,
+    #[arg(long, env = "MD_REDIS_HISTORY")]
+    history: Option<PathBuf>,
+    #[arg(long, env = "MD_REDIS_CERT")]
+    cert: Option<PathBuf>,
+    #[arg(long, env = "MD_REDIS_KEY")]
+    key: Option<PathBuf>,
+    #[arg(long, env = "MD_REDIS_MAX_PEERS")]
+    max_peers: Option<u32>,
+    #[arg(long, env = "MD_REDIS_OUTBOUND_CAPACITY")]
+    outbound_capacity: Option<usize>,
+    #[arg(long, env = "MD_REDIS_SLOW_CONSUMER")]
+    slow_consumer: Option<SlowConsumerPolicy>,
+}
+
+// How to reach the server, for every command that connects to one.
+#[derive(Args)]
+struct ConnectArgs {
+    #[arg(long, env = "MD_REDIS_SERVER", help = "host:port to connect to")]
+    server: Option<String>,
+    #[arg(long, env = "MD_REDIS_CA", conflicts_with = "pin")]
+    ca: Option<PathBuf>,
+    #[arg(long, env = "MD_REDIS_PIN")]
+    pin: Option<PathBuf>,
+    #[arg(long, env = "MD_REDIS_SERVER_NAME")]
+    server_name: Option<String>,
+    #[arg(long, env = "MD_REDIS_NO_RECEIPTS")]
+    no_receipts: bool,
+    #[arg(
+        long,
+        env = "MD_REDIS_IDENTITY_DIR",
+        help = "Where end-to-end keys are kept"
+    )]
+    identity_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ClientArgs {
+    username: String,
+    #[command(flatten)]
+    password: PasswordArgs,
+    #[command(flatten)]
+    connect: ConnectArgs,
+    #[cfg(feature = "gui")]
+    #[arg(long, help = "Chat on stdin and stdout instead of opening a window")]
+    headless: bool,
+    #[cfg(feature = "tui")]
+    #[arg(long, help = "Chat in a full-screen terminal UI, e.g. over SSH")]
+    tui: bool,
+}
+
+// Who the scriptable commands log in as; from the environment saves repeating it.
+#[derive(Args)]
+struct LoginArgs {
+    #[arg(long, short, env = "MD_REDIS_USER")]
+    user: String,
+    #[command(flatten)]
+    password: PasswordArgs,
+    #[command(flatten)]
+    connect: ConnectArgs,
+}
+
+// Passwords never go in argv, where other users and shell history could see them.
+#[derive(Args)]
+struct PasswordArgs {
+    #[arg(
+        long,
+        help = "Read the password from the terminal or stdin instead of MD_REDIS_PASSWORD"
+    )]
+    ask_password: bool,
+}
+
+impl PasswordArgs {
+    // Empty if neither was given, which is all a server without a users file needs.
+    fn password(&self) -> String {
+        if self.ask_password {
+            return read_password().unwrap_or_else(|e| fail("Could not read password", e));
+        }
+        env::var("MD_REDIS_PASSWORD").unwrap_or_default()
+    }
+}
+
+// Where the client chats.
+enum Frontend {
+    Console,
+    #[cfg(feature = "gui")]
+    Window,
+    #[cfg(feature = "tui")]
+    Terminal,
+}
+
+impl ClientArgs {
+    fn frontend(&self) -> Frontend {
+        #[cfg(feature = "tui")]
+        if self.tui {
+            return Frontend::Terminal;
+        }
+        #[cfg(feature = "gui")]
+        if !self.headless {
+            return Frontend::Window;
+        }
+        Frontend::Console
+    }
+}
+
+// Overwrites `target` with `value` if it was given.
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+impl Cli {
+    fn apply(&self, config: &mut Config) {
+        set(&mut config.log_level, self.log_level);
+        set(&mut config.heartbeat.interval, self.ping_interval);
+        set(&mut config.heartbeat.timeout, self.ping_timeout);
+        match &self.command {
+            Some(Command::Server(args)) => args.apply(config),
+            Some(Command::Client(args)) => args.connect.apply(config),
+            Some(
+                Command::Send { login, .. }
+                | Command::Listen { login, .. }
+                | Command::Pipe { login, .. },
+            ) => login.connect.apply(config),
+            Some(Command::Adduser { .. }) | None => {}
+        }
+    }
+}
+
+impl ServerArgs {
+    fn apply(&self, config: &mut Config) {
+        let server = &mut config.server;
+        set(&mut server.bind, self.bind);
+        set(&mut server.users, self.users.clone().map(Some));
+        set(&mut server.history, self.history.clone().map(Some));
+        set(&mut server.cert, self.cert.clone().map(Some));
+        set(&mut server.key, self.key.clone().map(Some));
+        set(&mut server.max_peers, self.max_peers);
+        set(&mut server.outbound_capacity, self.outbound_capacity);
+        set(&mut server.slow_consumer, self.slow_consumer);
+    }
+}
+
+impl ConnectArgs {
+    fn apply(&self, config: &mut Config) {
+        let client = &mut config.client;
+        set(&mut client.server, self.server.clone());
+        // Either flag replaces whatever trust the file set up.
+        if self.ca.is_some() || self.pin.is_some() {
+            client.ca = self.ca.clone();
+            client.pin = self.pin.clone();
+        }
+        set(&mut client.server_name, self.server_name.clone());
+        set(
+            &mut client.identity_dir,
+            self.identity_dir.clone().map(Some),
+        );
+        if self.no_receipts {
+            client.read_receipts = false;
+        }
+    }
+}
+
+fn fail(what: &str, e: impl Display) -> ! {
+    eprintln!("{}: {}", what, e);
+    process::exit(2);
+}
+
+// Prompts without echo on a terminal, otherwise takes the first line of stdin, so the
+// password never shows up in argv or shell history.
+fn read_password() -> io::Result<String> {
+    if io::stdin().is_terminal() {
+        return rpassword::prompt_password("Password: ");
+    }
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Logs go to stderr, leaving stdout to the conversation.
+fn log_to_stderr(level: LevelFilter) {
+    TermLogger::init(
+        level,
+        simplelog::Config::default(),
+        TerminalMode::Stderr,
+        ColorChoice::Auto,
+    )
+    .expect("Failed to set up logging");
+}
+
+// Connects as `username`, whose end-to-end key is kept in the identity directory.
+fn connect(config: &Config, username: &str, password: &str) -> Client {
+    let mut client_config = config
+        .client_config()
+        .unwrap_or_else(|e| fail("Invalid config", e));
+    let identity = config
+        .identity_path(username)
+        .unwrap_or_else(|e| fail("Invalid config", e));
+    if let Some(dir) = identity.parent() {
+        fs::create_dir_all(dir).unwrap_or_else(|e| fail("Could not create key directory", e));
+    }
+    client_config.identity = Some(identity);
+    let server_addr = client_config.server_addr.clone();
+    Client::connect_with(client_config, username, password)
+        .unwrap_or_else(|e| fail(&format!("Could not connect to {}", server_addr), e))
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mode = if args.len() > 1 {
-        &args[1]
-    } else {
-        "server"
+    let cli = Cli::parse();
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path).unwrap_or_else(|e| fail("Could not read config", e)),
+        None => Config::default(),
     };
-    if mode == "server" {
-        server::server();
-    } else {
-        let num = args[2].parse::<usize>().expect("Invalid peer number");
-        client::client(num);
+    cli.apply(&mut config);
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    match cli
+        .command
+        .unwrap_or(Command::Server(ServerArgs::default()))
+    {
+        Command::Server(_) => {
+            let server_config = config
+                .server_config()
+                .unwrap_or_else(|e| fail("Invalid config", e));
+            // The server reports through stderr itself; this is for what its libraries log.
+            SimpleLogger::init(config.log_level, simplelog::Config::default())
+                .expect("Failed to set up logging");
+            let bind_addr = server_config.bind_addr;
+            let mut server = Server::bind(server_config)
+                .unwrap_or_else(|e| fail(&format!("Could not bind to {}", bind_addr), e));
+            if let Some(users_file) = &config.server.users {
+                let auth = FileAuthenticator::load(users_file)
+                    .unwrap_or_else(|e| fail("Could not read users file", e));
+                server = server.with_authenticator(auth);
+            }
+            if let Ok(addr) = server.local_addr() {
+                println!("Listening on {}", addr);
+            }
+            let handle = server.shutdown_handle();
+            ctrlc::set_handler(move || handle.shutdown())
+                .expect("Failed to install signal handler");
+            server.run().expect("Server failed");
+        }
+        Command::Adduser {
+            users_file,
+            username,
+        } => {
+            if !is_valid_username(&username) {
+                fail("Invalid username", &username);
+            }
+            let password = read_password().unwrap_or_else(|e| fail("Could not read password", e));
+            let entry = FileAuthenticator::entry(&username, &password)
+                .unwrap_or_else(|e| fail("Could not hash password", e));
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(users_file)
+                .unwrap_or_else(|e| fail("Could not open users file", e));
+            writeln!(file, "{}", entry).unwrap_or_else(|e| fail("Could not write users file", e));
+        }
+        Command::Client(args) => {
+            let frontend = args.frontend();
+            if matches!(frontend, Frontend::Console) {
+                log_to_stderr(config.log_level);
+            } else {
+                // The screen has no room for logs, so they go to a file.
+                let log_file = File::create(format!("client_{}.log", args.username))
+                    .unwrap_or_else(|e| fail("Could not create log file", e));
+                WriteLogger::init(config.log_level, simplelog::Config::default(), log_file)
+                    .expect("Failed to set up logging");
+            }
+            let client = connect(&config, &args.username, &args.password.password());
+            match frontend {
+                Frontend::Console => console::run(client),
+                #[cfg(feature = "gui")]
+                Frontend::Window => gui::run(client),
+                #[cfg(feature = "tui")]
+                Frontend::Terminal => {
+                    tui::run(client).unwrap_or_else(|e| fail("Terminal UI failed", e))
+                }
+            }
+        }
+        Command::Send { login, to, text } => {
+            log_to_stderr(config.log_level);
+            let client = connect(&config, &login.user, &login.password.password());
+            let result = script::send(&client, &to, &text);
+            client.close();
+            result.unwrap_or_else(|e| fail("Not sent", e));
+        }
+        Command::Listen { login, json } => {
+            log_to_stderr(config.log_level);
+            let client = connect(&config, &login.user, &login.password.password());
+            match script::listen(&client, json) {
+                // Whoever was reading has gone, e.g. `listen | head`
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+                Err(e) => fail("Could not write to stdout", e),
+                Ok(()) => {}
+            }
+            client.close();
+        }
+        Command::Pipe { login, to } => {
+            log_to_stderr(config.log_level);
+            let client = connect(&config, &login.user, &login.password.password());
+            script::pipe(&client, &to).unwrap_or_else(|e| fail("Pipe failed", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from([
+            "md-redis",
+            "--ping-interval",
+            "0",
+            "client",
+            "alice",
+            "--server",
+            "example.com:9000",
+            "--no-receipts",
+        ]);
+        let mut config = Config::parse("[client]\nserver = \"file.example.com:1\"").unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.client.server, "example.com:9000");
+        assert!(!config.client.read_receipts);
+        assert_eq!(config.heartbeat.interval, 0.0);
+
+        let cli = Cli::parse_from(["md-redis", "server", "--slow-consumer", "drop-newest"]);
+        cli.apply(&mut config);
+        assert_eq!(config.server.slow_consumer, SlowConsumerPolicy::DropNewest);
+        assert!(Cli::try_parse_from(["md-redis", "adduser", "users.txt"]).is_err());
+        // Passwords stay out of argv
+        assert!(Cli::try_parse_from(["md-redis", "adduser", "users.txt", "bob", "pw"]).is_err());
+        assert!(Cli::try_parse_from(["md-redis", "client", "bob", "pw"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "md-redis",
+                "send",
+                "-u",
+                "bob",
+                "--to",
+                "3",
+                "--password",
+                "pw",
+                "hi"
+            ])
+            .is_err()
+        );
+
+        let cli = Cli::parse_from(["md-redis", "send", "-u", "bob", "--to", "3", "hi there"]);
+        let Some(Command::Send { login, to, text }) = cli.command else {
+            panic!("not a send command");
+        };
+        assert_eq!((login.user.as_str(), to.as_str()), ("bob", "3"));
+        assert_eq!(text, "hi there");
+        assert!(!login.password.ask_password);
+        assert!(Cli::try_parse_from(["md-redis", "pipe", "-u", "bob"]).is_err());
     }
 }
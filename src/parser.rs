@@ -1,6 +1,187 @@
-#[derive(Debug,Clone,PartialEq)]
+use bytes::{Buf, BufMut};
+
+use crate::shared::{from_hex, to_hex};
+
+// Frame layout: magic (2) | version (1) | flags (1) | peer (u32 BE) | body length (u32 BE) | body
+// The body is the payload, preceded by the origin peer (u32 BE) when FLAG_ORIGIN is set
+// and then by the message id (u64 BE) when FLAG_ID is set.
+pub type PeerId = u32;
+
+// Peer 0 is the server itself; system traffic is sent from and to it.
+pub const SYSTEM_PEER: PeerId = 0;
+
+// Ids with the top bit set address rooms rather than single peers.
+pub const ROOM_BIT: PeerId = 0x8000_0000;
+// Sending here reaches every other connected peer; it is never handed out.
+pub const BROADCAST_PEER: PeerId = ROOM_BIT - 1;
+pub const MAX_PEER_ID: PeerId = BROADCAST_PEER - 1;
+
+// A peer's X25519 public key, published so others can encrypt to it.
+pub type IdentityKey = [u8; 32];
+
+pub fn is_room(id: PeerId) -> bool {
+    id & ROOM_BIT != 0
+}
+
+pub const MAGIC: [u8; 2] = *b"MR";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 12;
+pub const MAX_PAYLOAD: usize = 1024;
+// The most a chunked message may add up to once its frames are joined.
+pub const MAX_MESSAGE: usize = 1024 * 1024;
+
+// Set on every frame of a chunked message except the last one.
+pub const FLAG_MORE: u8 = 0b0000_0001;
+// The body starts with the peer that originally sent it, e.g. for room traffic.
+pub const FLAG_ORIGIN: u8 = 0b0000_0010;
+const ORIGIN_LEN: usize = 4;
+// The body carries the id the sending client gave the message, for acks and receipts.
+pub const FLAG_ID: u8 = 0b0000_0100;
+const ID_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireFormat {
+    Binary,
+    Legacy,
+}
+
+impl WireFormat {
+    // Old clients start every chunk with an ASCII digit, new ones with MAGIC.
+    pub fn detect(input: &[u8]) -> Option<WireFormat> {
+        match input.first() {
+            Some(b) if *b == MAGIC[0] => Some(WireFormat::Binary),
+            Some(b) if b.is_ascii_digit() => Some(WireFormat::Legacy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub version: u8,
+    // Everything but FLAG_ORIGIN and FLAG_ID, which follow from `origin` and `id`.
+    pub flags: u8,
+    pub peer: PeerId,
+    pub origin: Option<PeerId>,
+    pub id: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(peer: PeerId, payload: Vec<u8>, flags: u8) -> Self {
+        Frame {
+            version: VERSION,
+            flags: flags & !(FLAG_ORIGIN | FLAG_ID),
+            peer,
+            origin: None,
+            id: None,
+            payload,
+        }
+    }
+
+    pub fn with_origin(mut self, origin: Option<PeerId>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn with_id(mut self, id: Option<u64>) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.flags & FLAG_MORE != 0
+    }
+
+    fn body_len(&self) -> usize {
+        self.payload.len() + self.origin.map_or(0, |_| ORIGIN_LEN) + self.id.map_or(0, |_| ID_LEN)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.body_len()
+    }
+
+    pub fn encode_into<B: BufMut>(&self, dst: &mut B) {
+        let mut flags = self.flags;
+        if self.origin.is_some() {
+            flags |= FLAG_ORIGIN;
+        }
+        if self.id.is_some() {
+            flags |= FLAG_ID;
+        }
+        dst.put_slice(&MAGIC);
+        dst.put_u8(self.version);
+        dst.put_u8(flags);
+        dst.put_u32(self.peer);
+        dst.put_u32(self.body_len() as u32);
+        if let Some(origin) = self.origin {
+            dst.put_u32(origin);
+        }
+        if let Some(id) = self.id {
+            dst.put_u64(id);
+        }
+        dst.put_slice(&self.payload);
+    }
+
+    // Returns Ok(None) until `src` holds a complete frame; nothing is consumed in that case.
+    // The header is peeked through `chunk()`, so it has to be contiguous (true for slices and BytesMut).
+    pub fn decode<B: Buf>(src: &mut B) -> Result<Option<Frame>, ParseError> {
+        if src.remaining() < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = src.chunk();
+        if header.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        if header[..2] != MAGIC {
+            return Err(ParseError::BadMagic);
+        }
+        let version = header[2];
+        if version != VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+        let flags = header[3];
+        let peer = PeerId::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        let origin_len = if flags & FLAG_ORIGIN != 0 {
+            ORIGIN_LEN
+        } else {
+            0
+        };
+        let id_len = if flags & FLAG_ID != 0 { ID_LEN } else { 0 };
+        let prefix_len = origin_len + id_len;
+        if length < prefix_len {
+            return Err(ParseError::NoContent);
+        }
+        if length - prefix_len > MAX_PAYLOAD {
+            return Err(ParseError::PayloadTooLarge(length - prefix_len));
+        }
+        if src.remaining() < HEADER_LEN + length {
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let origin = (origin_len > 0).then(|| src.get_u32());
+        let id = (id_len > 0).then(|| src.get_u64());
+        let mut payload = vec![0; length - prefix_len];
+        src.copy_to_slice(&mut payload);
+        Ok(Some(Frame {
+            version,
+            flags: flags & !(FLAG_ORIGIN | FLAG_ID),
+            peer,
+            origin,
+            id,
+            payload,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
-    pub peer: usize,
+    pub peer: PeerId,
+    // Who actually sent it, when `peer` is a room rather than the sender.
+    pub origin: Option<PeerId>,
+    // Set by the sending client when it wants to hear about delivery.
+    pub id: Option<u64>,
     pub content: Vec<u8>,
     pub has_more: bool,
 }
@@ -11,79 +192,525 @@ pub enum ParseError {
     PeerNotUsize,
     NoContent,
     NoEnding,
+    BadMagic,
+    UnsupportedVersion(u8),
+    PayloadTooLarge(usize),
+    MessageTooLarge(usize),
+    PeerOutOfRange(PeerId),
+    BadSystemMessage,
+    UnknownSystemMessage,
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        let has_more = frame.has_more();
+        Message {
+            peer: frame.peer,
+            origin: frame.origin,
+            id: frame.id,
+            content: frame.payload,
+            has_more,
+        }
+    }
 }
 
 impl Message {
-    pub fn new(peer: usize, content: Vec<u8>, has_more: bool) -> Self {
-        Message { peer, content, has_more }
+    pub fn new(peer: PeerId, content: Vec<u8>, has_more: bool) -> Self {
+        Message { peer, origin: None, id: None, content, has_more }
+    }
+
+    // The peer the message should be shown as coming from.
+    pub fn sender(&self) -> PeerId {
+        self.origin.unwrap_or(self.peer)
+    }
+
+    fn chunks(content: &[u8]) -> Vec<&[u8]> {
+        if content.is_empty() {
+            return vec![content];
+        }
+        content.chunks(MAX_PAYLOAD).collect()
+    }
+
+    pub fn encode(peer: PeerId, content: &[u8]) -> Vec<Vec<u8>> {
+        Self::encode_from(peer, None, content)
     }
 
-    pub fn parse(input: &Vec<u8>) -> Result<Self, ParseError> {
+    pub fn encode_from(peer: PeerId, origin: Option<PeerId>, content: &[u8]) -> Vec<Vec<u8>> {
+        Self::encode_with_id(peer, origin, None, content)
+    }
+
+    // Every chunk carries the id, like the origin, so it survives reassembly.
+    pub fn encode_with_id(
+        peer: PeerId,
+        origin: Option<PeerId>,
+        id: Option<u64>,
+        content: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let chunks = Self::chunks(content);
+        let last = chunks.len() - 1;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let flags = if i < last { FLAG_MORE } else { 0 };
+                let frame = Frame::new(peer, chunk.to_vec(), flags)
+                    .with_origin(origin)
+                    .with_id(id);
+                let mut part = Vec::with_capacity(frame.encoded_len());
+                frame.encode_into(&mut part);
+                part
+            })
+            .collect()
+    }
+}
+
+// The original 4-digit ASCII format: "PPPP" + content + '0' | '1' (more chunks follow).
+// It has no length prefix, so a chunk is whatever a single read returned.
+pub mod legacy {
+    use super::{Frame, Message, ParseError, PeerId};
+
+    // Anything wider would not fit the four ASCII digits.
+    pub const MAX_PEER: PeerId = 9999;
+
+    pub fn decode(input: &[u8]) -> Result<Message, ParseError> {
         let mut input = input.iter();
         let peer = input.by_ref().take(4).cloned().collect::<Vec<_>>();
-        let peer = String::from_utf8(peer);
-        if peer.is_err() {
-            return Err(ParseError::PeerNotUsize);
-        }
-        let peer = peer.unwrap();
+        let peer = String::from_utf8(peer).map_err(|_| ParseError::PeerNotUsize)?;
         if peer.len() != 4 {
             return Err(ParseError::PeerLessThanFour);
         }
-        let peer = peer.parse::<usize>();
-        if peer.is_err() {
-            return Err(ParseError::PeerNotUsize);
+        let peer = peer
+            .parse::<PeerId>()
+            .map_err(|_| ParseError::PeerNotUsize)?;
+        let content = input.cloned().collect::<Vec<_>>();
+        match content.split_last() {
+            None => Err(ParseError::NoContent),
+            Some((ending, content)) if *ending == b'0' || *ending == b'1' => {
+                Ok(Message::new(peer, content.to_vec(), *ending == b'1'))
+            }
+            Some(_) => Err(ParseError::NoEnding),
         }
-        let peer = peer.unwrap();
-        let content = input.collect::<Vec<_>>();
-        if content.is_empty() {
-            return Err(ParseError::NoContent);
+    }
+
+    fn encode_chunk(peer: PeerId, content: &[u8], has_more: bool) -> Result<Vec<u8>, ParseError> {
+        if peer > MAX_PEER {
+            return Err(ParseError::PeerOutOfRange(peer));
         }
-        let length = content.len();
-        if *content[length - 1] == b'1' || *content[length - 1] == b'0' {
-            let has_more = *content[length - 1] == b'1';
-
-            let content = content
-                .into_iter()
-                .take(length - 1)
-                .cloned()
-                .collect::<Vec<_>>();
-            return Ok(Message {
+        let mut res = format!("{:04}", peer).into_bytes();
+        res.extend_from_slice(content);
+        res.push(if has_more { b'1' } else { b'0' });
+        Ok(res)
+    }
+
+    // Rewrites binary frames for a connection that only speaks the legacy format.
+    pub fn transcode(parts: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, ParseError> {
+        parts
+            .iter()
+            .filter_map(|part| Frame::decode(&mut part.as_slice()).transpose())
+            .map(|frame| {
+                // Legacy clients can't address rooms or broadcasts, so show who actually sent it.
+                let frame = frame?;
+                let peer = frame.origin.unwrap_or(frame.peer);
+                encode_chunk(peer, &frame.payload, frame.has_more())
+            })
+            .collect()
+    }
+}
+
+// Everything sent from or to SYSTEM_PEER. The payload is "TAG:fields", with any
+// free text last so it may itself contain ':'.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SystemMessage {
+    // First thing a client sends; nothing else is routed until it succeeds.
+    Login { username: String, password: String },
+    Peers(Vec<(PeerId, String)>),
+    PeerJoined { peer: PeerId, name: String },
+    PeerLeft(PeerId),
+    Error { code: ErrorCode, message: String },
+    ServerInfo { protocol: u8, peer: PeerId },
+    Shutdown { reason: String },
+    // Requests from clients; the creator joins the new room straight away.
+    CreateRoom { name: String },
+    JoinRoom(PeerId),
+    LeaveRoom(PeerId),
+    ListRooms,
+    // Replies and notifications from the server.
+    RoomCreated { room: PeerId, name: String },
+    RoomJoined { room: PeerId, peer: PeerId },
+    RoomLeft { room: PeerId, peer: PeerId },
+    Rooms(Vec<(PeerId, String)>),
+    // A client announcing its identity key; the server passes it on as PeerKey.
+    PublishKey(IdentityKey),
+    PeerKey { peer: PeerId, key: IdentityKey },
+    // The target of a direct message is offline; it is held until they log in again.
+    Queued { peer: PeerId, id: Option<u64> },
+    // `peer` wasn't reading fast enough, so the server dropped a message sent to it.
+    Dropped { peer: PeerId, id: Option<u64> },
+    // Message `id` reached `peer`'s socket.
+    Delivered { peer: PeerId, id: u64 },
+    // Sent by a recipient for the sender of message `id`; the server swaps in the reader as `peer`.
+    Read { peer: PeerId, id: u64 },
+    // Message `id` to `peer` could not be routed; sent instead of Error for messages with an id.
+    Failed { peer: PeerId, id: u64, code: ErrorCode, message: String },
+    // Up to `limit` messages exchanged with `peer` (or broadcast, for BROADCAST_PEER) older
+    // than `before`, or the latest ones without it. Answered with HistoryEntry messages,
+    // oldest first, then HistoryEnd.
+    HistoryQuery { peer: PeerId, limit: u32, before: Option<u64> },
+    HistoryEntry { id: u64, timestamp: u64, src: PeerId, dst: PeerId, content: Vec<u8> },
+    HistoryEnd(PeerId),
+    // Heartbeats, sent both ways; a Ping is answered with a Pong.
+    Ping,
+    Pong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    InvalidMessage,
+    PeerNotFound,
+    ServerFull,
+    RoomNotFound,
+    InvalidRoomName,
+    NotInRoom,
+    RateLimited,
+    AuthFailed,
+    NotLoggedIn,
+    QueueFull,
+    MessageTooLarge,
+    // Someone is logged in under the name; may be our own connection the server hasn't
+    // noticed is gone yet, so worth trying again.
+    NameInUse,
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorCode::InvalidMessage => 1,
+            ErrorCode::PeerNotFound => 2,
+            ErrorCode::ServerFull => 3,
+            ErrorCode::RoomNotFound => 4,
+            ErrorCode::InvalidRoomName => 5,
+            ErrorCode::NotInRoom => 6,
+            ErrorCode::RateLimited => 7,
+            ErrorCode::AuthFailed => 8,
+            ErrorCode::NotLoggedIn => 9,
+            ErrorCode::QueueFull => 10,
+            ErrorCode::MessageTooLarge => 11,
+            ErrorCode::NameInUse => 12,
+            ErrorCode::Unknown(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::InvalidMessage,
+            2 => ErrorCode::PeerNotFound,
+            3 => ErrorCode::ServerFull,
+            4 => ErrorCode::RoomNotFound,
+            5 => ErrorCode::InvalidRoomName,
+            6 => ErrorCode::NotInRoom,
+            7 => ErrorCode::RateLimited,
+            8 => ErrorCode::AuthFailed,
+            9 => ErrorCode::NotLoggedIn,
+            10 => ErrorCode::QueueFull,
+            11 => ErrorCode::MessageTooLarge,
+            12 => ErrorCode::NameInUse,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl SystemMessage {
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        SystemMessage::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let text = match self {
+            SystemMessage::Login { username, password } => {
+                format!("LOGIN:{}:{}", username, password)
+            }
+            SystemMessage::Peers(peers) => format!("PEERS:{}", join_named(peers)),
+            SystemMessage::PeerJoined { peer, name } => format!("JOINED:{}:{}", peer, name),
+            SystemMessage::PeerLeft(peer) => format!("LEFT:{}", peer),
+            SystemMessage::Error { code, message } => format!("ERROR:{}:{}", code.code(), message),
+            SystemMessage::ServerInfo { protocol, peer } => format!("INFO:{}:{}", protocol, peer),
+            SystemMessage::Shutdown { reason } => format!("SHUTDOWN:{}", reason),
+            SystemMessage::CreateRoom { name } => format!("ROOM_CREATE:{}", name),
+            SystemMessage::JoinRoom(room) => format!("ROOM_JOIN:{}", room),
+            SystemMessage::LeaveRoom(room) => format!("ROOM_LEAVE:{}", room),
+            SystemMessage::ListRooms => "ROOM_LIST:".to_string(),
+            SystemMessage::RoomCreated { room, name } => format!("ROOM_CREATED:{}:{}", room, name),
+            SystemMessage::RoomJoined { room, peer } => format!("ROOM_JOINED:{}:{}", room, peer),
+            SystemMessage::RoomLeft { room, peer } => format!("ROOM_LEFT:{}:{}", room, peer),
+            SystemMessage::Rooms(rooms) => format!("ROOMS:{}", join_named(rooms)),
+            SystemMessage::PublishKey(key) => format!("KEY_PUBLISH:{}", to_hex(key)),
+            SystemMessage::PeerKey { peer, key } => format!("KEY:{}:{}", peer, to_hex(key)),
+            SystemMessage::Queued { peer, id } => {
+                let id = id.map(|id| id.to_string()).unwrap_or_default();
+                format!("QUEUED:{}:{}", peer, id)
+            }
+            SystemMessage::Dropped { peer, id } => {
+                let id = id.map(|id| id.to_string()).unwrap_or_default();
+                format!("DROPPED:{}:{}", peer, id)
+            }
+            SystemMessage::Delivered { peer, id } => format!("DELIVERED:{}:{}", peer, id),
+            SystemMessage::Read { peer, id } => format!("READ:{}:{}", peer, id),
+            SystemMessage::Failed {
+                peer,
+                id,
+                code,
+                message,
+            } => format!("FAILED:{}:{}:{}:{}", peer, id, code.code(), message),
+            SystemMessage::HistoryQuery {
                 peer,
+                limit,
+                before,
+            } => {
+                let before = before.map(|id| id.to_string()).unwrap_or_default();
+                format!("HISTORY:{}:{}:{}", peer, limit, before)
+            }
+            // The content may be sealed, i.e. binary, so it travels as hex.
+            SystemMessage::HistoryEntry {
+                id,
+                timestamp,
+                src,
+                dst,
                 content,
-                has_more,
-            });
-        }
-        return Err(ParseError::NoEnding);
-    }
-
-    fn encode_inner(peer: usize, content: &Vec<u8>)->(Vec<u8>,bool){
-        let peer = format!("{:04}", peer);
-        let has_more = if content.len() > 1024 { b'1' } else { b'0' };
-        let content = content.iter().take(1024);
-        let res = peer.as_bytes().into_iter().chain(content).chain(vec![has_more].iter()).cloned().collect::<Vec<u8>>();
-        (res, has_more == b'1')
-    }
-
-    pub fn encode(peer: usize, content: &Vec<u8>) -> Vec<Vec<u8>> {
-        let mut res = Vec::new();
-        let mut content = content.clone();
-        let (part, mut has_more) = Self::encode_inner(peer, &content);
-        content = content.into_iter().skip(1024).collect::<Vec<_>>();
-        res.push(part);
-        while has_more {
-            let (part, has_more_inner) = Self::encode_inner(peer, &content);
-            res.push(part);
-            content = content.into_iter().skip(1024).collect::<Vec<_>>();
-            has_more = has_more_inner;
+            } => format!(
+                "HISTORY_ENTRY:{}:{}:{}:{}:{}",
+                id,
+                timestamp,
+                src,
+                dst,
+                to_hex(content)
+            ),
+            SystemMessage::HistoryEnd(peer) => format!("HISTORY_END:{}", peer),
+            SystemMessage::Ping => "PING:".to_string(),
+            SystemMessage::Pong => "PONG:".to_string(),
+        };
+        text.into_bytes()
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self, ParseError> {
+        let text = std::str::from_utf8(input).map_err(|_| ParseError::BadSystemMessage)?;
+        let (tag, fields) = text
+            .split_once(':')
+            .ok_or(ParseError::BadSystemMessage)?;
+        match tag {
+            "LOGIN" => {
+                let (username, password) =
+                    fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                Ok(SystemMessage::Login {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            "PEERS" => Ok(SystemMessage::Peers(split_named(fields)?)),
+            "JOINED" => {
+                let (peer, name) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                Ok(SystemMessage::PeerJoined {
+                    peer: parse_field(peer)?,
+                    name: name.to_string(),
+                })
+            }
+            "LEFT" => Ok(SystemMessage::PeerLeft(parse_field(fields)?)),
+            "ERROR" => {
+                let (code, message) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                Ok(SystemMessage::Error {
+                    code: ErrorCode::from_code(parse_field(code)?),
+                    message: message.to_string(),
+                })
+            }
+            "INFO" => {
+                let (protocol, peer) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                Ok(SystemMessage::ServerInfo {
+                    protocol: parse_field(protocol)?,
+                    peer: parse_field(peer)?,
+                })
+            }
+            "SHUTDOWN" => Ok(SystemMessage::Shutdown {
+                reason: fields.to_string(),
+            }),
+            "ROOM_CREATE" => Ok(SystemMessage::CreateRoom {
+                name: fields.to_string(),
+            }),
+            "ROOM_JOIN" => Ok(SystemMessage::JoinRoom(parse_field(fields)?)),
+            "ROOM_LEAVE" => Ok(SystemMessage::LeaveRoom(parse_field(fields)?)),
+            "ROOM_LIST" => Ok(SystemMessage::ListRooms),
+            "ROOM_CREATED" => {
+                let (room, name) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                Ok(SystemMessage::RoomCreated {
+                    room: parse_field(room)?,
+                    name: name.to_string(),
+                })
+            }
+            "ROOM_JOINED" | "ROOM_LEFT" => {
+                let (room, peer) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                let (room, peer) = (parse_field(room)?, parse_field(peer)?);
+                if tag == "ROOM_JOINED" {
+                    Ok(SystemMessage::RoomJoined { room, peer })
+                } else {
+                    Ok(SystemMessage::RoomLeft { room, peer })
+                }
+            }
+            "ROOMS" => Ok(SystemMessage::Rooms(split_named(fields)?)),
+            "KEY_PUBLISH" => Ok(SystemMessage::PublishKey(parse_key(fields)?)),
+            "KEY" => {
+                let (peer, key) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                Ok(SystemMessage::PeerKey {
+                    peer: parse_field(peer)?,
+                    key: parse_key(key)?,
+                })
+            }
+            "QUEUED" | "DROPPED" => {
+                let (peer, id) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                let peer = parse_field(peer)?;
+                let id = if id.is_empty() {
+                    None
+                } else {
+                    Some(parse_field(id)?)
+                };
+                if tag == "QUEUED" {
+                    Ok(SystemMessage::Queued { peer, id })
+                } else {
+                    Ok(SystemMessage::Dropped { peer, id })
+                }
+            }
+            "DELIVERED" | "READ" => {
+                let (peer, id) = fields.split_once(':').ok_or(ParseError::BadSystemMessage)?;
+                let (peer, id) = (parse_field(peer)?, parse_field(id)?);
+                if tag == "DELIVERED" {
+                    Ok(SystemMessage::Delivered { peer, id })
+                } else {
+                    Ok(SystemMessage::Read { peer, id })
+                }
+            }
+            "FAILED" => {
+                let mut fields = fields.splitn(4, ':');
+                let (Some(peer), Some(id), Some(code), Some(message)) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(ParseError::BadSystemMessage);
+                };
+                Ok(SystemMessage::Failed {
+                    peer: parse_field(peer)?,
+                    id: parse_field(id)?,
+                    code: ErrorCode::from_code(parse_field(code)?),
+                    message: message.to_string(),
+                })
+            }
+            "HISTORY" => {
+                let mut fields = fields.split(':');
+                let (Some(peer), Some(limit), Some(before), None) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(ParseError::BadSystemMessage);
+                };
+                Ok(SystemMessage::HistoryQuery {
+                    peer: parse_field(peer)?,
+                    limit: parse_field(limit)?,
+                    before: if before.is_empty() {
+                        None
+                    } else {
+                        Some(parse_field(before)?)
+                    },
+                })
+            }
+            "HISTORY_ENTRY" => {
+                let mut fields = fields.split(':');
+                let (Some(id), Some(timestamp), Some(src), Some(dst), Some(content), None) = (
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                ) else {
+                    return Err(ParseError::BadSystemMessage);
+                };
+                Ok(SystemMessage::HistoryEntry {
+                    id: parse_field(id)?,
+                    timestamp: parse_field(timestamp)?,
+                    src: parse_field(src)?,
+                    dst: parse_field(dst)?,
+                    content: from_hex(content).ok_or(ParseError::BadSystemMessage)?,
+                })
+            }
+            "HISTORY_END" => Ok(SystemMessage::HistoryEnd(parse_field(fields)?)),
+            "PING" => Ok(SystemMessage::Ping),
+            "PONG" => Ok(SystemMessage::Pong),
+            _ => Err(ParseError::UnknownSystemMessage),
         }
-        return res;
     }
+
+    pub fn to_frames(&self) -> Vec<Vec<u8>> {
+        Message::encode(SYSTEM_PEER, &self.encode())
+    }
+}
+
+// Names end up in comma/equals separated lists, so keep them to a safe alphabet.
+fn is_valid_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn is_valid_room_name(name: &str) -> bool {
+    is_valid_name(name)
+}
+
+pub fn is_valid_username(name: &str) -> bool {
+    is_valid_name(name)
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str) -> Result<T, ParseError> {
+    field.trim().parse().map_err(|_| ParseError::BadSystemMessage)
+}
+
+fn parse_key(field: &str) -> Result<IdentityKey, ParseError> {
+    let key = from_hex(field.trim()).ok_or(ParseError::BadSystemMessage)?;
+    key.try_into().map_err(|_| ParseError::BadSystemMessage)
+}
+
+// "id=name,id=name", as used by peer and room lists.
+fn join_named(entries: &[(PeerId, String)]) -> String {
+    entries
+        .iter()
+        .map(|(id, name)| format!("{}={}", id, name))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn split_named(fields: &str) -> Result<Vec<(PeerId, String)>, ParseError> {
+    if fields.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    fields
+        .split(',')
+        .map(|entry| {
+            let (id, name) = entry.split_once('=').unwrap_or((entry, ""));
+            Ok((parse_field(id)?, name.to_string()))
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn decode_one(input: &[u8]) -> Message {
+        Frame::decode(&mut &input[..]).unwrap().unwrap().into()
+    }
+
+    fn legacy_encode(peer: PeerId, content: &[u8]) -> Result<Vec<Vec<u8>>, ParseError> {
+        legacy::transcode(&Message::encode(peer, content))
+    }
+
     #[test]
     fn test_parse_valid_simple_message() {
         // Format: "0042" + "Hello!" + "0"
@@ -91,10 +718,10 @@ mod tests {
         input.extend_from_slice("Hello!".as_bytes());
         input.push(b'0');
         
-        let message = Message::parse(&input).unwrap();
+        let message = legacy::decode(&input).unwrap();
         assert_eq!(message.peer, 42);
         assert_eq!(message.content, "Hello!".as_bytes().to_vec());
-        assert_eq!(message.has_more, false);
+        assert!(!message.has_more);
     }
 
     #[test]
@@ -104,10 +731,10 @@ mod tests {
         input.extend_from_slice("data".as_bytes());
         input.push(b'1');
         
-        let message = Message::parse(&input).unwrap();
+        let message = legacy::decode(&input).unwrap();
         assert_eq!(message.peer, 123);
         assert_eq!(message.content, "data".as_bytes().to_vec());
-        assert_eq!(message.has_more, true);
+        assert!(message.has_more);
     }
 
     #[test]
@@ -117,7 +744,7 @@ mod tests {
         input.extend_from_slice("Hello!".as_bytes());
         input.push(b'0');
         
-        let result = Message::parse(&input);
+        let result = legacy::decode(&input);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), ParseError::PeerNotUsize);
     }
@@ -127,7 +754,7 @@ mod tests {
         // Input too short for peer
         let input = "12".as_bytes().to_vec();
         
-        let result = Message::parse(&input);
+        let result = legacy::decode(&input);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), ParseError::PeerLessThanFour);
     }
@@ -137,7 +764,7 @@ mod tests {
         // Only peer, no content or flag
         let input = "0042".as_bytes().to_vec();
         
-        let result = Message::parse(&input);
+        let result = legacy::decode(&input);
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), ParseError::NoContent);
     }
@@ -149,9 +776,9 @@ mod tests {
         input.extend_from_slice("Hello!".as_bytes());
         input.push(b'x'); // Invalid flag
         
-        let result = Message::parse(&input);
+        let result = legacy::decode(&input);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), ParseError::NoContent);
+        assert_eq!(result.unwrap_err(), ParseError::NoEnding);
     }
 
     #[test]
@@ -161,16 +788,16 @@ mod tests {
         input.extend_from_slice(&[0xFF, 0x00, 0xAA, 0x55]); // Binary data
         input.push(b'0');
         
-        let message = Message::parse(&input).unwrap();
+        let message = legacy::decode(&input).unwrap();
         assert_eq!(message.peer, 1);
         assert_eq!(message.content, vec![0xFF, 0x00, 0xAA, 0x55]);
-        assert_eq!(message.has_more, false);
+        assert!(!message.has_more);
     }
 
     #[test]
-    fn test_encode_simple_message() {
+    fn test_legacy_encode_simple_message() {
         let content = "Hello, World!".as_bytes().to_vec();
-        let result = Message::encode(42, &content);
+        let result = legacy_encode(42, &content).unwrap();
         
         assert_eq!(result.len(), 1); // Single chunk
         
@@ -179,10 +806,10 @@ mod tests {
     }
 
     #[test]
-    fn test_encode_large_message() {
+    fn test_legacy_encode_large_message() {
         // Create content larger than 1024 bytes
         let content = vec![b'X'; 2000];
-        let result = Message::encode(123, &content);
+        let result = legacy_encode(123, &content).unwrap();
         
         assert_eq!(result.len(), 2); // Should be split into 2 chunks
         
@@ -201,10 +828,10 @@ mod tests {
     }
 
     #[test]
-    fn test_encode_exactly_1024_bytes() {
+    fn test_legacy_encode_exactly_1024_bytes() {
         // Content exactly 1024 bytes
         let content = vec![b'A'; 1024];
-        let result = Message::encode(1, &content);
+        let result = legacy_encode(1, &content).unwrap();
         
         assert_eq!(result.len(), 1); // Single chunk
         
@@ -216,9 +843,9 @@ mod tests {
     }
 
     #[test]
-    fn test_encode_empty_content() {
+    fn test_legacy_encode_empty_content() {
         let content = vec![];
-        let result = Message::encode(999, &content);
+        let result = legacy_encode(999, &content).unwrap();
         
         assert_eq!(result.len(), 1);
         
@@ -238,10 +865,10 @@ mod tests {
         let encoded = Message::encode(original_peer, &original_content);
         assert_eq!(encoded.len(), 1);
         
-        let parsed = Message::parse(&encoded[0]).unwrap();
+        let parsed = decode_one(&encoded[0]);
         assert_eq!(parsed.peer, original_peer);
         assert_eq!(parsed.content, original_content);
-        assert_eq!(parsed.has_more, false);
+        assert!(!parsed.has_more);
     }
 
     #[test]
@@ -254,20 +881,340 @@ mod tests {
         assert_eq!(encoded.len(), 2);
         
         // Parse first chunk
-        let first_chunk = Message::parse(&encoded[0]).unwrap();
+        let first_chunk = decode_one(&encoded[0]);
         assert_eq!(first_chunk.peer, original_peer);
         assert_eq!(first_chunk.content.len(), 1024);
-        assert_eq!(first_chunk.has_more, true);
+        assert!(first_chunk.has_more);
         
         // Parse second chunk
-        let second_chunk = Message::parse(&encoded[1]).unwrap();
+        let second_chunk = decode_one(&encoded[1]);
         assert_eq!(second_chunk.peer, original_peer);
         assert_eq!(second_chunk.content.len(), 476); // 1500 - 1024
-        assert_eq!(second_chunk.has_more, false);
+        assert!(!second_chunk.has_more);
         
         // Reconstruct original content
         let mut reconstructed = first_chunk.content;
         reconstructed.extend(second_chunk.content);
         assert_eq!(reconstructed, original_content);
     }
+
+    #[test]
+    fn test_frame_encode_layout() {
+        let frame = Frame::new(42, b"Hello!".to_vec(), 0);
+        let mut buf = Vec::new();
+        frame.encode_into(&mut buf);
+
+        let mut expected = b"MR".to_vec();
+        expected.extend_from_slice(&[VERSION, 0, 0, 0, 0, 42, 0, 0, 0, 6]);
+        expected.extend_from_slice(b"Hello!");
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_frame_roundtrip_payload_ending_in_flag_bytes() {
+        // The old format misread payloads ending in '0' or '1'
+        for payload in [b"count: 10".to_vec(), b"1".to_vec(), b"0".to_vec()] {
+            let frame = Frame::new(7, payload.clone(), 0);
+            let mut buf = Vec::new();
+            frame.encode_into(&mut buf);
+
+            let decoded = Frame::decode(&mut buf.as_slice()).unwrap().unwrap();
+            assert_eq!(decoded, frame);
+            assert_eq!(decoded.payload, payload);
+        }
+    }
+
+    #[test]
+    fn test_frame_decode_incomplete() {
+        let frame = Frame::new(1, b"partial".to_vec(), 0);
+        let mut buf = Vec::new();
+        frame.encode_into(&mut buf);
+
+        for cut in [0, 5, HEADER_LEN, buf.len() - 1] {
+            let mut input = &buf[..cut];
+            assert_eq!(Frame::decode(&mut input), Ok(None));
+            assert_eq!(input.len(), cut); // nothing consumed
+        }
+    }
+
+    #[test]
+    fn test_frame_decode_leaves_following_frame() {
+        let mut buf = Vec::new();
+        Frame::new(1, b"first".to_vec(), FLAG_MORE).encode_into(&mut buf);
+        Frame::new(2, b"second".to_vec(), 0).encode_into(&mut buf);
+
+        let mut input = buf.as_slice();
+        let first = Frame::decode(&mut input).unwrap().unwrap();
+        assert_eq!(first.peer, 1);
+        assert!(first.has_more());
+        let second = Frame::decode(&mut input).unwrap().unwrap();
+        assert_eq!(second.peer, 2);
+        assert_eq!(second.payload, b"second".to_vec());
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_frame_decode_bad_header() {
+        let mut buf = Vec::new();
+        Frame::new(1, b"x".to_vec(), 0).encode_into(&mut buf);
+
+        let mut bad_magic = buf.clone();
+        bad_magic[1] = b'X';
+        assert_eq!(Frame::decode(&mut bad_magic.as_slice()), Err(ParseError::BadMagic));
+
+        let mut bad_version = buf.clone();
+        bad_version[2] = 9;
+        assert_eq!(
+            Frame::decode(&mut bad_version.as_slice()),
+            Err(ParseError::UnsupportedVersion(9))
+        );
+
+        let mut too_large = buf.clone();
+        too_large[8..12].copy_from_slice(&(MAX_PAYLOAD as u32 + 1).to_be_bytes());
+        assert_eq!(
+            Frame::decode(&mut too_large.as_slice()),
+            Err(ParseError::PayloadTooLarge(MAX_PAYLOAD + 1))
+        );
+    }
+
+    #[test]
+    fn test_encode_large_message() {
+        let content = vec![b'X'; 2000];
+        let result = Message::encode(123, &content);
+        assert_eq!(result.len(), 2);
+
+        let first = Frame::decode(&mut result[0].as_slice()).unwrap().unwrap();
+        assert_eq!(first.peer, 123);
+        assert_eq!(first.payload.len(), 1024);
+        assert!(first.has_more());
+
+        let second = Frame::decode(&mut result[1].as_slice()).unwrap().unwrap();
+        assert_eq!(second.payload.len(), 976);
+        assert!(!second.has_more());
+    }
+
+    #[test]
+    fn test_encode_empty_content() {
+        let result = Message::encode(999, &[]);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].len(), HEADER_LEN);
+        let parsed = decode_one(&result[0]);
+        assert_eq!(parsed, Message::new(999, vec![], false));
+    }
+
+    #[test]
+    fn test_detect_wire_format() {
+        assert_eq!(WireFormat::detect(b"0042Hello0"), Some(WireFormat::Legacy));
+        assert_eq!(WireFormat::detect(&Message::encode(42, b"Hello")[0]), Some(WireFormat::Binary));
+        assert_eq!(WireFormat::detect(b""), None);
+        assert_eq!(WireFormat::detect(b"?"), None);
+    }
+
+    #[test]
+    fn test_wide_peer_ids() {
+        // Ids past 9999 used to produce a 5-digit header that could not be parsed back
+        for peer in [10_000, 123_456, PeerId::MAX] {
+            let encoded = Message::encode(peer, b"hi");
+            assert_eq!(decode_one(&encoded[0]), Message::new(peer, b"hi".to_vec(), false));
+        }
+    }
+
+    #[test]
+    fn test_legacy_rejects_wide_peer_ids() {
+        assert_eq!(
+            legacy_encode(legacy::MAX_PEER + 1, b"hi"),
+            Err(ParseError::PeerOutOfRange(legacy::MAX_PEER + 1))
+        );
+        assert!(legacy_encode(legacy::MAX_PEER, b"hi").is_ok());
+    }
+
+    #[test]
+    fn test_legacy_shows_origin_of_broadcasts() {
+        let encoded = legacy::transcode(&Message::encode_from(BROADCAST_PEER, Some(7), b"hi"));
+        assert_eq!(encoded, Ok(vec![b"0007hi0".to_vec()]));
+    }
+
+    #[test]
+    fn test_system_message_roundtrip() {
+        let messages = [
+            SystemMessage::Login {
+                username: "alice".to_string(),
+                password: "hunter2:with:colons".to_string(),
+            },
+            SystemMessage::Peers(vec![(1, "alice".to_string()), (10_000, "bob".to_string())]),
+            SystemMessage::Peers(vec![]),
+            SystemMessage::PeerJoined {
+                peer: 42,
+                name: "carol".to_string(),
+            },
+            SystemMessage::PeerLeft(PeerId::MAX),
+            SystemMessage::error(ErrorCode::InvalidMessage, "Invalid message"),
+            SystemMessage::error(ErrorCode::PeerNotFound, "Peer not found: a:b"),
+            SystemMessage::error(ErrorCode::ServerFull, ""),
+            SystemMessage::error(ErrorCode::Unknown(999), "from a newer server"),
+            SystemMessage::ServerInfo {
+                protocol: VERSION,
+                peer: 7,
+            },
+            SystemMessage::Shutdown {
+                reason: "Server is shutting down".to_string(),
+            },
+            SystemMessage::CreateRoom {
+                name: "general".to_string(),
+            },
+            SystemMessage::JoinRoom(ROOM_BIT | 1),
+            SystemMessage::LeaveRoom(ROOM_BIT | 1),
+            SystemMessage::ListRooms,
+            SystemMessage::RoomCreated {
+                room: ROOM_BIT | 2,
+                name: "random".to_string(),
+            },
+            SystemMessage::RoomJoined {
+                room: ROOM_BIT | 2,
+                peer: 5,
+            },
+            SystemMessage::RoomLeft {
+                room: ROOM_BIT | 2,
+                peer: 5,
+            },
+            SystemMessage::Rooms(vec![]),
+            SystemMessage::Rooms(vec![
+                (ROOM_BIT | 1, "general".to_string()),
+                (ROOM_BIT | 2, "random".to_string()),
+            ]),
+            SystemMessage::error(ErrorCode::RoomNotFound, "Room not found"),
+            SystemMessage::error(ErrorCode::InvalidRoomName, "Invalid room name"),
+            SystemMessage::error(ErrorCode::NotInRoom, "Not in room"),
+            SystemMessage::error(ErrorCode::AuthFailed, "Bad username or password"),
+            SystemMessage::error(ErrorCode::NotLoggedIn, "Log in first"),
+            SystemMessage::error(ErrorCode::NameInUse, "Already logged in"),
+            SystemMessage::PublishKey([7; 32]),
+            SystemMessage::PeerKey {
+                peer: 3,
+                key: [0xAB; 32],
+            },
+            SystemMessage::Queued { peer: 4, id: None },
+            SystemMessage::Queued {
+                peer: 4,
+                id: Some(u64::MAX),
+            },
+            SystemMessage::Dropped { peer: 5, id: None },
+            SystemMessage::Dropped { peer: 5, id: Some(6) },
+            SystemMessage::Delivered { peer: 2, id: 17 },
+            SystemMessage::Read { peer: 2, id: 17 },
+            SystemMessage::Failed {
+                peer: 9,
+                id: 3,
+                code: ErrorCode::PeerNotFound,
+                message: "Peer: not found".to_string(),
+            },
+            SystemMessage::error(ErrorCode::QueueFull, "Queue full"),
+            SystemMessage::HistoryQuery {
+                peer: 2,
+                limit: 20,
+                before: None,
+            },
+            SystemMessage::HistoryQuery {
+                peer: BROADCAST_PEER,
+                limit: 5,
+                before: Some(99),
+            },
+            SystemMessage::HistoryEntry {
+                id: 7,
+                timestamp: 1_700_000_000_000,
+                src: 1,
+                dst: 2,
+                content: vec![0xFF, b':', 0],
+            },
+            SystemMessage::HistoryEntry {
+                id: 8,
+                timestamp: 0,
+                src: 2,
+                dst: 1,
+                content: vec![],
+            },
+            SystemMessage::HistoryEnd(2),
+            SystemMessage::Ping,
+            SystemMessage::Pong,
+        ];
+        for msg in messages {
+            assert_eq!(SystemMessage::decode(&msg.encode()), Ok(msg.clone()));
+            let frames = msg.to_frames();
+            let decoded = decode_one(&frames[0]);
+            assert_eq!(decoded.peer, SYSTEM_PEER);
+            assert_eq!(SystemMessage::decode(&decoded.content), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn test_system_message_wire_format() {
+        let peers = SystemMessage::Peers(vec![(1, "alice".to_string()), (2, "bob".to_string())]);
+        assert_eq!(peers.encode(), b"PEERS:1=alice,2=bob".to_vec());
+        // Lists from older servers carry bare ids
+        assert_eq!(
+            SystemMessage::decode(b"PEERS:1,2"),
+            Ok(SystemMessage::Peers(vec![(1, String::new()), (2, String::new())]))
+        );
+        assert_eq!(
+            SystemMessage::error(ErrorCode::PeerNotFound, "Peer not found").encode(),
+            b"ERROR:2:Peer not found".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_system_message_decode_errors() {
+        assert_eq!(SystemMessage::decode(b"PEERS"), Err(ParseError::BadSystemMessage));
+        assert_eq!(SystemMessage::decode(b"PEERS:1,x"), Err(ParseError::BadSystemMessage));
+        assert_eq!(SystemMessage::decode(b"LOGIN:alice"), Err(ParseError::BadSystemMessage));
+        assert_eq!(SystemMessage::decode(b"KEY_PUBLISH:abcd"), Err(ParseError::BadSystemMessage));
+        assert_eq!(SystemMessage::decode(b"KEY:1:zz"), Err(ParseError::BadSystemMessage));
+        assert_eq!(SystemMessage::decode(b"ERROR:oops"), Err(ParseError::BadSystemMessage));
+        assert_eq!(SystemMessage::decode(b"WHAT:1"), Err(ParseError::UnknownSystemMessage));
+        assert_eq!(SystemMessage::decode(&[0xFF, b':']), Err(ParseError::BadSystemMessage));
+    }
+
+    #[test]
+    fn test_frame_with_origin_roundtrip() {
+        let frame =
+            Frame::new(ROOM_BIT | 3, b"to the room".to_vec(), FLAG_MORE).with_origin(Some(9));
+        let mut buf = Vec::new();
+        frame.encode_into(&mut buf);
+        assert_eq!(buf[3], FLAG_MORE | FLAG_ORIGIN);
+        assert_eq!(buf.len(), frame.encoded_len());
+
+        let decoded = Frame::decode(&mut buf.as_slice()).unwrap().unwrap();
+        assert_eq!(decoded, frame);
+        let msg = Message::from(decoded);
+        assert_eq!(msg.peer, ROOM_BIT | 3);
+        assert_eq!(msg.sender(), 9);
+    }
+
+    #[test]
+    fn test_message_id_survives_chunking() {
+        let content = vec![b'x'; MAX_PAYLOAD + 10];
+        let parts = Message::encode_with_id(2, Some(5), Some(0xDEAD_BEEF_0000_0001), &content);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0][3], FLAG_MORE | FLAG_ORIGIN | FLAG_ID);
+        let mut reader = crate::shared::FrameReader::new();
+        let messages = reader.feed(&parts.concat()).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, Some(0xDEAD_BEEF_0000_0001));
+        assert_eq!(messages[0].sender(), 5);
+        assert_eq!(messages[0].content, content);
+
+        let plain = decode_one(&Message::encode(2, b"no id")[0]);
+        assert_eq!(plain.id, None);
+    }
+
+    #[test]
+    fn test_room_addressing() {
+        assert!(!is_room(SYSTEM_PEER));
+        assert!(!is_room(MAX_PEER_ID));
+        assert!(is_room(ROOM_BIT));
+        assert!(is_valid_room_name("team-chat_2"));
+        assert!(!is_valid_room_name(""));
+        assert!(!is_valid_room_name("a,b"));
+        assert!(!is_valid_room_name("a=b"));
+        assert!(!is_valid_room_name(&"x".repeat(33)));
+    }
 }
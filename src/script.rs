@@ -0,0 +1,242 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use base64::prelude::*;
+use md_redis::{
+    Client, Event, Status,
+    parser::{BROADCAST_PEER, Message, PeerId, SYSTEM_PEER, is_room},
+};
+use serde::Serialize;
+
+use crate::console::describe;
+
+// How long to wait for the server to tell us about a peer, or about a message we sent.
+const WAIT: Duration = Duration::from_secs(10);
+const POLL: Duration = Duration::from_millis(20);
+
+// How much of stdin `pipe` sends at a time.
+const CHUNK: usize = 4096;
+
+// A received message as `listen --json` prints it, one per line.
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    // The conversation: the sender, a room or BROADCAST_PEER.
+    peer: PeerId,
+    from: PeerId,
+    id: Option<u64>,
+    content: Cow<'a, str>,
+    // "utf-8", or "base64" for content that isn't text.
+    encoding: &'static str,
+}
+
+impl<'a> From<&'a Message> for JsonMessage<'a> {
+    fn from(msg: &'a Message) -> Self {
+        let (content, encoding) = match str::from_utf8(&msg.content) {
+            Ok(text) => (Cow::Borrowed(text), "utf-8"),
+            Err(_) => (Cow::Owned(BASE64_STANDARD.encode(&msg.content)), "base64"),
+        };
+        JsonMessage {
+            peer: msg.peer,
+            from: msg.sender(),
+            id: msg.id,
+            content,
+            encoding,
+        }
+    }
+}
+
+// Checks `done` until it holds or WAIT is up.
+fn wait_until(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + WAIT;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(POLL);
+    }
+    true
+}
+
+// Waits until `to`, an id, a username or "*", names someone we can send to. For a peer
+// that takes the server having sent their key; a name is looked up once the peer list,
+// which has us in it, has arrived.
+fn resolve(client: &Client, to: &str) -> Result<PeerId, String> {
+    let mut peer = None;
+    let found = wait_until(|| {
+        peer = client.resolve_peer(to);
+        match peer {
+            Some(peer) => peer == BROADCAST_PEER || is_room(peer) || client.key(peer).is_some(),
+            None => !client.peers().is_empty(),
+        }
+    });
+    match peer {
+        Some(SYSTEM_PEER) => Err("Peer 0 is the server, use the client's /commands".to_string()),
+        Some(peer) if found => Ok(peer),
+        Some(peer) => Err(format!("No key for peer {}, are they online?", peer)),
+        None => Err(format!("Unknown peer: {}", to)),
+    }
+}
+
+// Whether direct message `id` made it to the server, once there is news about it. The
+// client forgets messages once they are read or have failed, so this goes by the Status
+// events rather than Client::status.
+fn outcome(id: u64, status: &Status) -> Option<Result<(), String>> {
+    match status {
+        Status::Sent => None,
+        Status::Failed(reason) => Some(Err(format!("Message {} failed: {}", id, reason))),
+        Status::Queued | Status::Delivered | Status::Read => Some(Ok(())),
+    }
+}
+
+fn unconfirmed(id: u64) -> String {
+    format!("No confirmation for message {}", id)
+}
+
+// Waits for `pipe`'s reader to see news about a direct message, and fails if it did not
+// arrive.
+fn confirm(statuses: &Mutex<HashMap<u64, Status>>, id: u64) -> Result<(), String> {
+    let mut result = None;
+    wait_until(|| {
+        let statuses = statuses.lock().unwrap();
+        result = statuses.get(&id).and_then(|status| outcome(id, status));
+        result.is_some()
+    });
+    result.unwrap_or_else(|| Err(unconfirmed(id)))
+}
+
+// `md-redis send`: one message, then done once the server has it.
+pub fn send(client: &Client, to: &str, text: &str) -> Result<(), String> {
+    let peer = resolve(client, to)?;
+    let id = client
+        .send(peer, text.as_bytes())
+        .map_err(|e| format!("Could not send to {}: {}", peer, e))?;
+    let Some(id) = id else {
+        return Ok(());
+    };
+    let deadline = Instant::now() + WAIT;
+    while let Some(event) = client.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        if let Event::Status {
+            id: sent, status, ..
+        } = event
+            && sent == id
+            && let Some(result) = outcome(id, &status)
+        {
+            return result;
+        }
+    }
+    Err(unconfirmed(id))
+}
+
+// `md-redis listen`: every message that arrives, on stdout until the client is closed.
+pub fn listen(client: &Client, json: bool) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    while let Some(event) = client.recv() {
+        let Event::Message(msg) = &event else {
+            continue;
+        };
+        if json {
+            serde_json::to_writer(&mut out, &JsonMessage::from(msg))?;
+            writeln!(out)?;
+        } else {
+            writeln!(out, "{}", describe(&event))?;
+        }
+        out.flush()?;
+        let _ = client.mark_read(msg.peer);
+    }
+    Ok(())
+}
+
+// `md-redis pipe`: stdin goes to `to` as it comes, what they send goes to stdout as is.
+// Ends with stdin, once the server has confirmed what was sent.
+pub fn pipe(client: &Client, to: &str) -> Result<(), String> {
+    let peer = resolve(client, to)?;
+    let statuses = Mutex::new(HashMap::new());
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut out = io::stdout().lock();
+            while let Some(event) = client.recv() {
+                let msg = match event {
+                    Event::Message(msg) => msg,
+                    Event::Status { id, status, .. } => {
+                        statuses.lock().unwrap().insert(id, status);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if msg.peer != peer {
+                    continue;
+                }
+                if out
+                    .write_all(&msg.content)
+                    .and_then(|_| out.flush())
+                    .is_err()
+                {
+                    break;
+                }
+                let _ = client.mark_read(peer);
+            }
+        });
+        let result = send_stdin(client, peer, &statuses);
+        client.close();
+        result
+    })
+}
+
+fn send_stdin(
+    client: &Client,
+    peer: PeerId,
+    statuses: &Mutex<HashMap<u64, Status>>,
+) -> Result<(), String> {
+    let mut stdin = io::stdin().lock();
+    let mut buf = vec![0; CHUNK];
+    let mut ids = vec![];
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("Could not read stdin: {}", e)),
+        };
+        let id = client
+            .send(peer, &buf[..n])
+            .map_err(|e| format!("Could not send to {}: {}", peer, e))?;
+        ids.extend(id);
+    }
+    ids.into_iter().try_for_each(|id| confirm(statuses, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md_redis::parser::ROOM_BIT;
+
+    #[test]
+    fn test_json_message() {
+        let mut msg = Message::new(ROOM_BIT | 1, b"hi \"there\"".to_vec(), false);
+        msg.origin = Some(3);
+        msg.id = Some(7);
+        let json = serde_json::to_string(&JsonMessage::from(&msg)).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"peer":{},"from":3,"id":7,"content":"hi \"there\"","encoding":"utf-8"}}"#,
+                msg.peer
+            )
+        );
+
+        // Not text, so it comes through intact rather than with replacement characters
+        let msg = Message::new(2, vec![0xff, 0x00, b'a'], false);
+        let json = serde_json::to_string(&JsonMessage::from(&msg)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"peer":2,"from":2,"id":null,"content":"/wBh","encoding":"base64"}"#
+        );
+    }
+}
@@ -1,223 +1,2331 @@
 use std::{
-    collections::HashMap,
-    net::{TcpListener, TcpStream},
+    collections::{BTreeSet, HashMap, VecDeque},
+    io::{self, ErrorKind, Write},
+    mem,
+    net::{IpAddr, Shutdown, SocketAddr},
+    path::PathBuf,
     sync::{
-        Arc, Mutex, OnceLock,
-        mpsc::{self, SendError, Sender, TryRecvError},
+        Arc,
+        atomic::{AtomicBool, Ordering},
     },
-    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use mio::{
+    Events, Interest, Poll, Registry, Token, Waker,
+    net::{TcpListener, TcpStream},
 };
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    parser::Message,
-    shared::{ExtractError, extract_message, write_message},
+    auth::{AllowAll, Authenticator},
+    history::History,
+    parser::{
+        BROADCAST_PEER, ErrorCode, IdentityKey, MAX_PEER_ID, Message, ParseError, PeerId, ROOM_BIT,
+        SYSTEM_PEER, SystemMessage, VERSION, WireFormat, is_room, is_valid_room_name,
+        is_valid_username, legacy,
+    },
+    shared::{ExtractError, FrameReader, Heartbeat},
+    tls::{self, ServerTls, Stream},
 };
 
 type RawMessage = Vec<Vec<u8>>;
 
+// Peer 0 is never handed out, so its token is free for the listener.
+const LISTENER: Token = Token(SYSTEM_PEER as usize);
+// Peer ids are u32, so this can't collide with a client token.
+const WAKER: Token = Token(usize::MAX);
+
+// Hands out the lowest free id so ids stay small on long-running servers,
+// and refuses once every id up to `max` is taken instead of wrapping.
+struct IdAllocator {
+    next: PeerId,
+    free: BTreeSet<PeerId>,
+    max: PeerId,
+}
+impl IdAllocator {
+    fn new(max: PeerId) -> Self {
+        IdAllocator {
+            next: SYSTEM_PEER + 1,
+            free: BTreeSet::new(),
+            max,
+        }
+    }
+    fn allocate(&mut self) -> Option<PeerId> {
+        if let Some(id) = self.free.pop_first() {
+            return Some(id);
+        }
+        if self.next > self.max {
+            return None;
+        }
+        let id = self.next;
+        self.next += 1;
+        Some(id)
+    }
+    // Takes a specific id, e.g. one restored from disk, so it is never handed out.
+    fn reserve(&mut self, id: PeerId) {
+        if id >= self.next {
+            self.free.extend(self.next..id);
+            self.next = id + 1;
+        } else {
+            self.free.remove(&id);
+        }
+    }
+    fn is_exhausted(&self) -> bool {
+        self.free.is_empty() && self.next > self.max
+    }
+    fn release(&mut self, id: PeerId) {
+        if id != SYSTEM_PEER && id < self.next {
+            self.free.insert(id);
+        }
+    }
+}
+
+// Token bucket: up to `burst` sends at once, refilled by one every `interval`.
+struct RateLimiter {
+    limit: BroadcastLimit,
+    tokens: u32,
+    refilled: Instant,
+}
+impl RateLimiter {
+    fn new(limit: BroadcastLimit) -> Self {
+        RateLimiter {
+            limit,
+            tokens: limit.burst,
+            refilled: Instant::now(),
+        }
+    }
+    fn try_take(&mut self, now: Instant) -> bool {
+        if self.limit.interval.is_zero() {
+            return true;
+        }
+        let earned = (now - self.refilled).as_nanos() / self.limit.interval.as_nanos();
+        if earned > 0 {
+            let earned = earned.min(self.limit.burst as u128) as u32;
+            self.tokens = (self.tokens + earned).min(self.limit.burst);
+            self.refilled = if self.tokens == self.limit.burst {
+                now
+            } else {
+                self.refilled + self.limit.interval * earned
+            };
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+// A message waiting for a client's socket.
+struct Outbound {
+    // Encoded parts; `started` is set once any of its bytes have gone out.
+    parts: VecDeque<Vec<u8>>,
+    started: bool,
+    // The client it came from, if not the server. Only these are ever dropped, and the
+    // sender is told when they are.
+    from: Option<PeerId>,
+    id: Option<u64>,
+    // Whether to send `from` a Delivered ack once the last part is written.
+    ack: bool,
+}
+impl Outbound {
+    fn system(msg: RawMessage) -> Self {
+        Outbound {
+            parts: msg.into(),
+            started: false,
+            from: None,
+            id: None,
+            ack: false,
+        }
+    }
+    fn client(msg: RawMessage, src: PeerId, id: Option<u64>, ack: bool) -> Self {
+        Outbound {
+            from: Some(src),
+            id,
+            ack: ack && id.is_some(),
+            ..Self::system(msg)
+        }
+    }
+    fn transcode_legacy(&mut self) -> bool {
+        match legacy::transcode(self.parts.make_contiguous()) {
+            Ok(parts) => {
+                self.parts = parts.into();
+                true
+            }
+            Err(e) => {
+                eprintln!("Cannot send to legacy client: {:?}", e);
+                false
+            }
+        }
+    }
+    fn is_droppable(&self) -> bool {
+        self.from.is_some() && !self.started
+    }
+}
+
+enum Pushed {
+    Queued,
+    // Made room by dropping this one, which may be the message that was pushed.
+    Dropped(Outbound),
+    // Nothing could be dropped, or the policy says to disconnect.
+    Overflow,
+    // The client's wire format can't carry it, e.g. a legacy client and a sender id past
+    // four digits.
+    Unsupported(Outbound),
+}
+
+struct Connection {
+    stream: Stream<TcpStream>,
+    reader: FrameReader,
+    // Messages waiting for the socket; `written` counts bytes of the front part already sent.
+    outbound: VecDeque<Outbound>,
+    written: usize,
+    format: WireFormat,
+    broadcasts: RateLimiter,
+    // Set once the client has logged in; nothing is routed to or from it before then.
+    name: Option<String>,
+    // Senders and ids of acked messages that have gone out since the server last collected them.
+    delivered: Vec<(PeerId, u64)>,
+    // When anything last arrived from the client.
+    last_seen: Instant,
+}
+impl Connection {
+    fn new(stream: Stream<TcpStream>, broadcast_limit: BroadcastLimit) -> Self {
+        Connection {
+            stream,
+            reader: FrameReader::new(),
+            outbound: VecDeque::new(),
+            written: 0,
+            format: WireFormat::Binary,
+            broadcasts: RateLimiter::new(broadcast_limit),
+            name: None,
+            delivered: vec![],
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, mut msg: Outbound, limit: OutboundLimit) -> Pushed {
+        if self.format == WireFormat::Legacy && !msg.transcode_legacy() {
+            return Pushed::Unsupported(msg);
+        }
+        if limit.capacity == 0 || self.outbound.len() < limit.capacity {
+            self.outbound.push_back(msg);
+            return Pushed::Queued;
+        }
+        let victim = match limit.policy {
+            SlowConsumerPolicy::Disconnect => return Pushed::Overflow,
+            SlowConsumerPolicy::DropNewest if msg.is_droppable() => return Pushed::Dropped(msg),
+            SlowConsumerPolicy::DropNewest => {
+                self.outbound.iter().rposition(Outbound::is_droppable)
+            }
+            SlowConsumerPolicy::DropOldest => self.outbound.iter().position(Outbound::is_droppable),
+        };
+        let Some(victim) = victim.and_then(|i| self.outbound.remove(i)) else {
+            return Pushed::Overflow;
+        };
+        self.outbound.push_back(msg);
+        Pushed::Dropped(victim)
+    }
+
+    // Replies go out in whatever format the client speaks, so re-encode what hasn't been started yet.
+    fn set_format(&mut self, format: WireFormat) {
+        if self.format == format {
+            return;
+        }
+        self.format = format;
+        if format == WireFormat::Legacy {
+            self.outbound
+                .retain_mut(|msg| msg.started || msg.transcode_legacy());
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some(msg) = self.outbound.front_mut() {
+            let Some(part) = msg.parts.front() else {
+                if msg.ack
+                    && let (Some(src), Some(id)) = (msg.from, msg.id)
+                {
+                    self.delivered.push((src, id));
+                }
+                self.outbound.pop_front();
+                continue;
+            };
+            match self.stream.write(&part[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    msg.started = true;
+                    self.written += n;
+                    if self.written == part.len() {
+                        msg.parts.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        // TLS holds on to encrypted records the socket didn't take yet.
+        match self.stream.flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.outbound.is_empty() || self.stream.wants_write()
+    }
+}
+
+// A direct message held for a user who was offline when it was sent.
+struct Queued {
+    at: Instant,
+    msg: Outbound,
+}
+
+struct Room {
+    name: String,
+    members: BTreeSet<PeerId>,
+    // The first history id of this room; ones before it belong to whoever had the id earlier.
+    since: u64,
+}
+
 struct GlobalState {
-    users: Mutex<HashMap<usize, Sender<RawMessage>>>,
-    next_index: usize,
+    users: HashMap<PeerId, Connection>,
+    ids: IdAllocator,
+    rooms: HashMap<PeerId, Room>,
+    // Hands out the low bits of room ids; rooms are addressed as ROOM_BIT | id.
+    room_ids: IdAllocator,
+    // Connections that failed mid-event; removed once the current read or event is handled.
+    dead: Vec<PeerId>,
+    // Join/leave updates are pointless while everyone is being disconnected.
+    shutting_down: bool,
+    broadcast_limit: BroadcastLimit,
+    authenticator: Box<dyn Authenticator>,
+    // When each address last failed to log in, while it still has to wait before trying again.
+    failed_logins: HashMap<IpAddr, Instant>,
+    login_delay: Duration,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    // Ids stay bound to a username for the server's lifetime, so returning users keep theirs.
+    names: HashMap<String, PeerId>,
+    // Published identity keys. The server only hands them out; it never holds a private key.
+    keys: HashMap<PeerId, IdentityKey>,
+    // Direct messages waiting for their recipient to log in again, oldest first.
+    offline: HashMap<PeerId, VecDeque<Queued>>,
+    offline_limit: OfflineLimit,
+    outbound_limit: OutboundLimit,
+    history: History,
+    heartbeat: Heartbeat,
 }
 impl GlobalState {
-    fn new() -> Self {
+    fn new(
+        config: &ServerConfig,
+        tls: Option<Arc<rustls::ServerConfig>>,
+        history: History,
+    ) -> Self {
+        // Names bound in earlier runs keep their ids.
+        let names = history.users().clone();
+        let mut ids = IdAllocator::new(config.max_peers.min(MAX_PEER_ID));
+        for id in names.values() {
+            ids.reserve(*id);
+        }
         GlobalState {
-            users: Mutex::new(HashMap::new()),
-            next_index: 1,
-        }
-    }
-    fn add_user(session: &mut Self, sender: Sender<RawMessage>) -> usize {
-        let mut users = session.users.lock().unwrap();
-        let next_index = session.next_index;
-        users.insert(next_index, sender);
-        session.next_index += 1;
-        let x = users.keys().cloned().collect::<Vec<usize>>();
-        let peers = GlobalState::peers_to_string(x.clone());
-        let senders = users.values().cloned().collect::<Vec<Sender<RawMessage>>>();
-        thread::spawn(move || {
-            let msg = Message::encode(0, format!("PEERS:{}", peers).as_bytes().to_vec().as_ref());
-            broadcast_message(senders, msg);
-        });
-        next_index
-    }
-    fn remove_user(session: &mut Self, index: usize) {
-        let mut users = session.users.lock().unwrap();
-        users.remove(&index);
-        let x = users.keys().cloned().collect::<Vec<usize>>();
-        let peers = GlobalState::peers_to_string(x.clone());
-        let senders = users.values().cloned().collect::<Vec<Sender<RawMessage>>>();
-        thread::spawn(move || {
-            let msg = Message::encode(0, format!("PEERS:{}", peers).as_bytes().to_vec().as_ref());
-            broadcast_message(senders, msg);
-        });
-    }
-    fn get_user(&self, index: usize) -> Option<Sender<RawMessage>> {
-        let users = self.users.lock().unwrap();
-        users.get(&index).cloned()
-    }
-    fn peers_to_string(users: Vec<usize>) -> String {
-        users
+            users: HashMap::new(),
+            ids,
+            rooms: HashMap::new(),
+            room_ids: IdAllocator::new(MAX_PEER_ID),
+            dead: vec![],
+            shutting_down: false,
+            broadcast_limit: config.broadcast_limit,
+            authenticator: Box::new(AllowAll),
+            failed_logins: HashMap::new(),
+            login_delay: config.login_delay,
+            tls,
+            names,
+            keys: HashMap::new(),
+            offline: HashMap::new(),
+            offline_limit: config.offline_limit,
+            outbound_limit: config.outbound_limit,
+            history,
+            heartbeat: config.heartbeat,
+        }
+    }
+    fn add_user(&mut self, registry: &Registry, stream: TcpStream) -> Option<PeerId> {
+        let mut stream = match &self.tls {
+            Some(config) => match Stream::server(stream, config.clone()) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to start TLS for client: {}", e);
+                    return None;
+                }
+            },
+            None => Stream::Plain(stream),
+        };
+        let next_index = self.ids.allocate()?;
+        let token = Token(next_index as usize);
+        if let Err(e) = registry.register(
+            stream.get_mut(),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            eprintln!("Failed to register client {}: {}", next_index, e);
+            self.ids.release(next_index);
+            return None;
+        }
+        self.users
+            .insert(next_index, Connection::new(stream, self.broadcast_limit));
+        Some(next_index)
+    }
+    // Binds the connection to `username`. A returning user gets their old id back, which
+    // moves the connection over to it; the id the connection ends up under is returned.
+    // Names are only bound when the authenticator proves who is logging in, otherwise the
+    // connection keeps the id it came with.
+    fn login(
+        &mut self,
+        registry: &Registry,
+        src: PeerId,
+        username: String,
+        password: &str,
+    ) -> Result<PeerId, HandleMessageError> {
+        if !is_valid_username(&username) {
+            return Err(HandleMessageError::AuthFailed("Invalid username"));
+        }
+        // Checking a password is slow and holds up everyone else, so an address that just
+        // got one wrong is turned away without checking until the delay has passed.
+        let now = Instant::now();
+        let delay = self.login_delay;
+        self.failed_logins.retain(|_, at| now - *at < delay);
+        let ip = self
+            .users
+            .get(&src)
+            .and_then(|conn| conn.stream.get_ref().peer_addr().ok())
+            .map(|addr| addr.ip());
+        if ip.is_some_and(|ip| self.failed_logins.contains_key(&ip)) {
+            return Err(HandleMessageError::AuthFailed(
+                "Too many attempts, try again later",
+            ));
+        }
+        if let Err(e) = self.authenticator.authenticate(&username, password) {
+            eprintln!("Client {} failed to log in as {}: {:?}", src, username, e);
+            if let Some(ip) = ip {
+                self.failed_logins.insert(ip, now);
+            }
+            return Err(HandleMessageError::AuthFailed("Bad username or password"));
+        }
+        let proven = self.authenticator.proves_identity();
+        if !proven && self.logged_in().iter().any(|(_, name)| *name == username) {
+            return Err(HandleMessageError::NameInUse);
+        }
+        let bound = self.names.get(&username).filter(|_| proven);
+        let index = match bound {
+            Some(&index) => {
+                // Proven logins take over from a connection that hasn't been noticed to have
+                // dropped yet, so a reconnect isn't shut out until the heartbeat times out.
+                if self.users.contains_key(&index) {
+                    eprintln!("{} logged in again, dropping client {}", username, index);
+                    self.dead.retain(|dead| *dead != index);
+                    self.remove_user(registry, index);
+                }
+                let Some(mut conn) = self.users.remove(&src) else {
+                    return Err(HandleMessageError::PeerNotFound(src));
+                };
+                let token = Token(index as usize);
+                if let Err(e) = registry.reregister(
+                    conn.stream.get_mut(),
+                    token,
+                    Interest::READABLE | Interest::WRITABLE,
+                ) {
+                    eprintln!("Failed to move client {} to {}: {}", src, index, e);
+                    self.users.insert(src, conn);
+                    self.dead.push(src);
+                    return Err(HandleMessageError::AuthFailed("Login failed"));
+                }
+                self.ids.release(src);
+                self.users.insert(index, conn);
+                index
+            }
+            None if proven => {
+                self.names.insert(username.clone(), src);
+                self.history.bind_user(&username, src);
+                src
+            }
+            None => src,
+        };
+        if let Some(conn) = self.users.get_mut(&index) {
+            conn.name = Some(username.clone());
+        }
+        let info = SystemMessage::ServerInfo {
+            protocol: VERSION,
+            peer: index,
+        };
+        self.send_to(index, info.to_frames());
+        // Only the newcomer needs the whole list; everyone else just hears about it.
+        // Keys of offline users too, so messages they queued can be opened.
+        let keys = self
+            .keys
             .iter()
-            .map(|k| k.to_string())
-            .collect::<Vec<String>>()
-            .join(",")
+            .filter(|(peer, _)| **peer != index)
+            .map(|(peer, key)| SystemMessage::PeerKey {
+                peer: *peer,
+                key: *key,
+            })
+            .collect::<Vec<_>>();
+        for msg in keys {
+            self.send_to(index, msg.to_frames());
+        }
+        let peers = self.logged_in();
+        self.send_to(index, SystemMessage::Peers(peers).to_frames());
+        self.deliver_offline(index);
+        let joined = SystemMessage::PeerJoined {
+            peer: index,
+            name: username,
+        };
+        self.broadcast_except(index, joined);
+        Ok(index)
+    }
+    // Ids that have logged in at some point, whether or not they are connected now.
+    fn is_known(&self, index: PeerId) -> bool {
+        self.names.values().any(|id| *id == index)
+    }
+    fn queue_offline(&mut self, peer: PeerId, msg: Outbound) -> Result<(), HandleMessageError> {
+        let now = Instant::now();
+        let expiry = self.offline_limit.expiry;
+        let queue = self.offline.entry(peer).or_default();
+        // Expired messages would be dropped on delivery anyway, so they don't count.
+        while queue
+            .front()
+            .is_some_and(|queued| now.duration_since(queued.at) >= expiry)
+        {
+            queue.pop_front();
+        }
+        if queue.len() >= self.offline_limit.quota {
+            return Err(HandleMessageError::QueueFull(peer));
+        }
+        queue.push_back(Queued { at: now, msg });
+        Ok(())
+    }
+    fn deliver_offline(&mut self, index: PeerId) {
+        let Some(queue) = self.offline.remove(&index) else {
+            return;
+        };
+        let now = Instant::now();
+        let expiry = self.offline_limit.expiry;
+        for queued in queue {
+            if now.duration_since(queued.at) < expiry {
+                self.send(index, queued.msg);
+            }
+        }
+    }
+    fn is_logged_in(&self, index: PeerId) -> bool {
+        self.users
+            .get(&index)
+            .is_some_and(|conn| conn.name.is_some())
+    }
+    fn logged_in(&self) -> Vec<(PeerId, String)> {
+        let mut peers = self
+            .users
+            .iter()
+            .filter_map(|(index, conn)| Some((*index, conn.name.clone()?)))
+            .collect::<Vec<_>>();
+        peers.sort_unstable();
+        peers
+    }
+    fn remove_user(&mut self, registry: &Registry, index: PeerId) {
+        let Some(mut conn) = self.users.remove(&index) else {
+            return;
+        };
+        if let Err(e) = registry.deregister(conn.stream.get_mut()) {
+            eprintln!("Failed to deregister client {}: {}", index, e);
+        }
+        // Once bound to a name, the id stays reserved for it.
+        if conn.name.is_none() {
+            self.ids.release(index);
+            return;
+        }
+        let rooms = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains(&index))
+            .map(|(id, _)| *id)
+            .collect::<Vec<PeerId>>();
+        for room in rooms {
+            self.leave_room(index, room).ok();
+        }
+        self.broadcast_except(index, SystemMessage::PeerLeft(index));
+        // Without a bound name the id goes to someone else next, so its key goes with it.
+        if !self.is_known(index) {
+            self.keys.remove(&index);
+            self.ids.release(index);
+        }
+    }
+    fn create_room(&mut self, src: PeerId, name: String) -> Result<(), HandleMessageError> {
+        if !is_valid_room_name(&name) {
+            return Err(HandleMessageError::InvalidRoomName(name));
+        }
+        let Some(id) = self.room_ids.allocate() else {
+            return Err(HandleMessageError::TooManyRooms);
+        };
+        let room = ROOM_BIT | id;
+        self.rooms.insert(
+            room,
+            Room {
+                name: name.clone(),
+                members: BTreeSet::new(),
+                since: self.history.next_id(),
+            },
+        );
+        self.send_to(src, SystemMessage::RoomCreated { room, name }.to_frames());
+        self.join_room(src, room)
+    }
+    fn join_room(&mut self, src: PeerId, room: PeerId) -> Result<(), HandleMessageError> {
+        let members = {
+            let entry = self
+                .rooms
+                .get_mut(&room)
+                .ok_or(HandleMessageError::RoomNotFound(room))?;
+            entry.members.insert(src);
+            entry.members.clone()
+        };
+        let msg = SystemMessage::RoomJoined { room, peer: src }.to_frames();
+        for member in members {
+            self.send_to(member, msg.clone());
+        }
+        Ok(())
+    }
+    fn leave_room(&mut self, src: PeerId, room: PeerId) -> Result<(), HandleMessageError> {
+        let entry = self
+            .rooms
+            .get_mut(&room)
+            .ok_or(HandleMessageError::RoomNotFound(room))?;
+        if !entry.members.remove(&src) {
+            return Err(HandleMessageError::NotInRoom(room));
+        }
+        let members = entry.members.clone();
+        if members.is_empty() {
+            self.rooms.remove(&room);
+            self.room_ids.release(room & !ROOM_BIT);
+        }
+        let msg = SystemMessage::RoomLeft { room, peer: src }.to_frames();
+        self.send_to(src, msg.clone());
+        for member in members {
+            self.send_to(member, msg.clone());
+        }
+        Ok(())
+    }
+    // Room history is for the room's members only.
+    fn send_history(
+        &mut self,
+        src: PeerId,
+        peer: PeerId,
+        limit: u32,
+        before: Option<u64>,
+    ) -> Result<(), HandleMessageError> {
+        let page = if is_room(peer) {
+            let since = match self.rooms.get(&peer) {
+                Some(room) if room.members.contains(&src) => room.since,
+                Some(_) => return Err(HandleMessageError::NotInRoom(peer)),
+                None => return Err(HandleMessageError::RoomNotFound(peer)),
+            };
+            self.history.query_room(peer, since, before, limit as usize)
+        } else {
+            self.history.query(src, peer, before, limit as usize)
+        };
+        let page = page
+            .into_iter()
+            .map(|record| SystemMessage::HistoryEntry {
+                id: record.id,
+                timestamp: record.timestamp,
+                src: record.src,
+                dst: record.dst,
+                content: record.content,
+            })
+            .collect::<Vec<_>>();
+        for entry in page {
+            self.send_to(src, entry.to_frames());
+        }
+        self.send_to(src, SystemMessage::HistoryEnd(peer).to_frames());
+        Ok(())
+    }
+    fn list_rooms(&mut self, src: PeerId) {
+        let mut rooms = self
+            .rooms
+            .iter()
+            .map(|(id, room)| (*id, room.name.clone()))
+            .collect::<Vec<_>>();
+        rooms.sort_unstable();
+        self.send_to(src, SystemMessage::Rooms(rooms).to_frames());
+    }
+    fn send_to(&mut self, index: PeerId, msg: RawMessage) -> bool {
+        self.send(index, Outbound::system(msg))
+    }
+    fn send(&mut self, index: PeerId, msg: Outbound) -> bool {
+        if !self.enqueue(index, msg) {
+            return false;
+        }
+        self.flush(index);
+        true
+    }
+    // Queues without writing anything. A full queue is handled by the slow-consumer policy:
+    // the sender of a dropped message is told, and a client that can't be helped that way
+    // is disconnected. A message the client can't receive at all is refused, and its
+    // sender told so.
+    fn enqueue(&mut self, index: PeerId, msg: Outbound) -> bool {
+        let limit = self.outbound_limit;
+        let Some(conn) = self.users.get_mut(&index) else {
+            return false;
+        };
+        if self.dead.contains(&index) {
+            return false;
+        }
+        match conn.push(msg, limit) {
+            Pushed::Queued => {}
+            Pushed::Dropped(victim) => {
+                if let Some(src) = victim.from {
+                    let notice = SystemMessage::Dropped {
+                        peer: index,
+                        id: victim.id,
+                    };
+                    self.send_to(src, notice.to_frames());
+                }
+            }
+            Pushed::Overflow => {
+                eprintln!("Client {} is not keeping up, dropping it", index);
+                self.dead.push(index);
+            }
+            Pushed::Unsupported(msg) => {
+                if let Some(src) = msg.from {
+                    let (code, message) = (ErrorCode::InvalidMessage, "Recipient can't receive it");
+                    let notice = match msg.id {
+                        Some(id) => SystemMessage::Failed {
+                            peer: index,
+                            id,
+                            code,
+                            message: message.to_string(),
+                        },
+                        None => SystemMessage::error(code, message),
+                    };
+                    self.send_to(src, notice.to_frames());
+                }
+                return false;
+            }
+        }
+        true
+    }
+    // Acks for what went out are queued for their senders, whose sockets are flushed in turn.
+    fn flush(&mut self, index: PeerId) {
+        let mut pending = vec![index];
+        while let Some(index) = pending.pop() {
+            let Some(conn) = self.users.get_mut(&index) else {
+                continue;
+            };
+            if let Err(e) = conn.flush() {
+                eprintln!("Failed to write to client {}: {}", index, e);
+                self.dead.push(index);
+                continue;
+            }
+            for (src, id) in mem::take(&mut conn.delivered) {
+                let ack = SystemMessage::Delivered { peer: index, id };
+                // Acks for senders who have since left are dropped.
+                if self.enqueue(src, Outbound::system(ack.to_frames())) {
+                    pending.push(src);
+                }
+            }
+        }
+    }
+    fn broadcast_except(&mut self, skip: PeerId, msg: SystemMessage) {
+        if self.shutting_down {
+            return;
+        }
+        let users = self.logged_in().into_iter().map(|(index, _)| index);
+        let users = users
+            .filter(|index| *index != skip)
+            .collect::<Vec<PeerId>>();
+        let msg = msg.to_frames();
+        for index in users {
+            self.send_to(index, msg.clone());
+        }
+    }
+    // Pings connections that have gone quiet and drops those that stayed quiet too long,
+    // which is the only way a half-open connection ever goes away.
+    fn heartbeat(&mut self, now: Instant) {
+        let mut quiet = vec![];
+        for (index, conn) in &self.users {
+            // Legacy clients predate heartbeats and would never answer.
+            if conn.format == WireFormat::Legacy {
+                continue;
+            }
+            let silence = now.duration_since(conn.last_seen);
+            if silence >= self.heartbeat.timeout {
+                eprintln!("Client {} missed its heartbeats, dropping it", index);
+                self.dead.push(*index);
+            } else if silence >= self.heartbeat.interval {
+                quiet.push(*index);
+            }
+        }
+        for index in quiet {
+            self.send_to(index, SystemMessage::Ping.to_frames());
+        }
+    }
+    fn reap(&mut self, registry: &Registry) {
+        while let Some(index) = self.dead.pop() {
+            self.remove_user(registry, index);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind_addr: SocketAddr,
+    // Most peer ids handed out at once, counting those kept for users who logged in before;
+    // further connections are turned away.
+    pub max_peers: PeerId,
+    // How long queued messages get to reach clients once shutdown starts.
+    pub drain_timeout: Duration,
+    pub broadcast_limit: BroadcastLimit,
+    // How long an address has to wait after a failed login before it may try again. Zero
+    // disables the wait.
+    pub login_delay: Duration,
+    pub offline_limit: OfflineLimit,
+    pub outbound_limit: OutboundLimit,
+    pub heartbeat: Heartbeat,
+    // Where routed messages are kept across restarts; without it history only lasts
+    // as long as the process, and only the latest history::MAX_RESIDENT messages of it.
+    pub history_path: Option<PathBuf>,
+    // Serve TLS instead of plain TCP.
+    pub tls: Option<ServerTls>,
+}
+
+// How often a single client may send to BROADCAST_PEER. A zero interval disables the limit.
+#[derive(Debug, Clone, Copy)]
+pub struct BroadcastLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+// How many direct messages are held for each offline user, and for how long. Messages past
+// the quota are refused; expired ones are dropped unseen. A zero quota disables queueing.
+#[derive(Debug, Clone, Copy)]
+pub struct OfflineLimit {
+    pub quota: usize,
+    pub expiry: Duration,
+}
+
+// How many messages may wait for one client's socket before `policy` kicks in. Messages
+// from the server itself are never dropped. A zero capacity disables the limit.
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundLimit {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+// What to do when a client's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            max_peers: MAX_PEER_ID,
+            drain_timeout: Duration::from_secs(5),
+            broadcast_limit: BroadcastLimit {
+                burst: 5,
+                interval: Duration::from_secs(1),
+            },
+            login_delay: Duration::from_secs(1),
+            offline_limit: OfflineLimit {
+                quota: 100,
+                expiry: Duration::from_secs(24 * 60 * 60),
+            },
+            outbound_limit: OutboundLimit {
+                capacity: 1000,
+                policy: SlowConsumerPolicy::DropOldest,
+            },
+            history_path: None,
+            heartbeat: Heartbeat::default(),
+            tls: None,
+        }
+    }
+}
+
+// Lets another thread stop a running server.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Err(e) = self.waker.wake() {
+            eprintln!("Failed to wake server for shutdown: {}", e);
+        }
     }
 }
 
-static SESSION: OnceLock<Mutex<GlobalState>> = OnceLock::new();
+pub struct Server {
+    poll: Poll,
+    listener: TcpListener,
+    session: GlobalState,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+}
+
+impl Server {
+    pub fn bind(config: ServerConfig) -> io::Result<Server> {
+        let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
+        let history = match &config.history_path {
+            Some(path) => History::open(path)?,
+            None => History::in_memory(),
+        };
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(config.bind_addr)?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        Ok(Server {
+            poll,
+            listener,
+            session: GlobalState::new(&config, tls, history),
+            shutdown: ShutdownHandle {
+                requested: Arc::new(AtomicBool::new(false)),
+                waker: Arc::new(waker),
+            },
+            drain_timeout: config.drain_timeout,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Checks logins against `authenticator` instead of letting anyone in.
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.session.authenticator = Box::new(authenticator);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let heartbeat = self.session.heartbeat;
+        let mut next_heartbeat = Instant::now() + heartbeat.interval;
+        while !self.shutdown.requested.load(Ordering::SeqCst) {
+            let timeout = heartbeat
+                .is_enabled()
+                .then(|| next_heartbeat.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            let session = &mut self.session;
+            let now = Instant::now();
+            if heartbeat.is_enabled() && now >= next_heartbeat {
+                session.heartbeat(now);
+                session.reap(self.poll.registry());
+                next_heartbeat = now + heartbeat.interval;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => accept_clients(&self.listener, self.poll.registry(), session),
+                    WAKER => {}
+                    Token(index) => {
+                        let index = index as PeerId;
+                        if event.is_readable() {
+                            read_client(session, self.poll.registry(), index);
+                        }
+                        if event.is_writable() {
+                            session.flush(index);
+                        }
+                    }
+                }
+                session.reap(self.poll.registry());
+            }
+        }
+        self.drain()
+    }
+
+    // Stops accepting, tells everyone, then gives queued messages until the drain timeout to go out.
+    fn drain(mut self) -> io::Result<()> {
+        println!(
+            "Shutting down, draining {} clients",
+            self.session.users.len()
+        );
+        self.poll.registry().deregister(&mut self.listener)?;
+        drop(self.listener);
+
+        let session = &mut self.session;
+        session.shutting_down = true;
+        let msg = SystemMessage::Shutdown {
+            reason: "Server is shutting down".to_string(),
+        };
+        let msg = msg.to_frames();
+        let users = session.users.keys().cloned().collect::<Vec<PeerId>>();
+        for index in users {
+            session.send_to(index, msg.clone());
+        }
+        session.reap(self.poll.registry());
+
+        let deadline = Instant::now() + self.drain_timeout;
+        let mut events = Events::with_capacity(1024);
+        while session.users.values().any(Connection::has_pending) {
+            let now = Instant::now();
+            if now >= deadline {
+                eprintln!("Drain timeout reached, dropping undelivered messages");
+                break;
+            }
+            if let Err(e) = self.poll.poll(&mut events, Some(deadline - now)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                if event.token() != WAKER && event.is_writable() {
+                    session.flush(event.token().0 as PeerId);
+                }
+            }
+            session.reap(self.poll.registry());
+        }
 
-pub fn server() {
-    SESSION.get_or_init(|| Mutex::new(GlobalState::new()));
-    let listener = TcpListener::bind("0.0.0.0:8000").expect("Could not bind to 8000");
+        for conn in session.users.values_mut() {
+            conn.stream.send_close_notify();
+            let _ = conn.stream.flush();
+            let _ = conn.stream.get_ref().shutdown(Shutdown::Both);
+        }
+        Ok(())
+    }
+}
+
+fn accept_clients(listener: &TcpListener, registry: &Registry, session: &mut GlobalState) {
     loop {
-        let (client_stream, _client_addr) = listener.accept().expect("Failed to accept connection");
-        client_stream
-            .set_nonblocking(true)
-            .expect("Failed to set non-blocking mode");
-        let client_stream = Arc::new(Mutex::new(client_stream));
-        thread::spawn(move || {
-            handler_chan(client_stream);
-        });
-        // handler_chan(&mut client_stream);
+        let mut client_stream = match listener.accept() {
+            Ok((client_stream, _client_addr)) => client_stream,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                return;
+            }
+        };
+        if session.ids.is_exhausted() {
+            eprintln!("No peer ids left, rejecting connection");
+            // TLS clients couldn't read a plaintext reply, so they are just disconnected.
+            if session.tls.is_none() {
+                let msg = SystemMessage::error(ErrorCode::ServerFull, "Server full").to_frames();
+                let _ = client_stream.write(&msg.concat());
+            }
+            continue;
+        }
+        session.add_user(registry, client_stream);
     }
 }
 
-fn handler_chan(client_stream: Arc<Mutex<TcpStream>>) {
-    let (tx, rx) = mpsc::channel::<RawMessage>();
-    let (internal_tx, internal_rx) = mpsc::channel::<()>();
-    let current_index = {
-        let mut session = SESSION.get().expect("Not Initialized").lock().unwrap();
-        GlobalState::add_user(&mut session, tx)
-    };
-    let read_stream = Arc::clone(&client_stream);
-    let h1 = thread::spawn(move || {
-        loop {
-            let message = extract_message(&mut read_stream.lock().unwrap());
-            if message.is_err() {
-                let err = message.unwrap_err();
+// Edge-triggered: keep reading until the socket would block.
+fn read_client(session: &mut GlobalState, registry: &Registry, mut current_index: PeerId) {
+    loop {
+        let Some(conn) = session.users.get_mut(&current_index) else {
+            return;
+        };
+        let messages = match conn.reader.read_from(&mut conn.stream) {
+            Ok(messages) => {
+                conn.last_seen = Instant::now();
+                if let Some(format) = conn.reader.format() {
+                    conn.set_format(format);
+                }
+                messages
+            }
+            Err(err) => {
                 match err {
                     ExtractError::IOError(e) => {
                         eprintln!("IO Error: {}", e);
-                        internal_tx.send(()).unwrap();
-                        break;
+                        session.dead.push(current_index);
+                        return;
                     }
                     ExtractError::InvalidMessage(parse_error) => {
                         eprintln!("Parse Error: {:?}", parse_error);
-                        let msg =
-                            Message::encode(0, "Invalid message".as_bytes().to_vec().as_ref());
-                        write_message(&mut read_stream.lock().unwrap(), msg);
+                        let msg = match parse_error {
+                            ParseError::MessageTooLarge(_) => SystemMessage::error(
+                                ErrorCode::MessageTooLarge,
+                                "Message too large",
+                            ),
+                            _ => SystemMessage::error(ErrorCode::InvalidMessage, "Invalid message"),
+                        };
+                        reply(session, current_index, msg);
                         continue;
                     }
                     ExtractError::NotReady => {
-                        continue; // Not ready, continue to read more data
+                        return; // Drained, wait for the next readiness event
                     }
                     ExtractError::Closed => {
-                        eprintln!("Connection closed by client");
-                        internal_tx.send(()).unwrap();
-                        break;
+                        eprintln!("Connection closed by client {}", current_index);
+                        session.dead.push(current_index);
+                        return;
                     }
                 }
             }
-            let message = message.unwrap();
+        };
 
-            let res = handle_message(current_index, message);
-            if res.is_err() {
-                let err = res.unwrap_err();
-                match err {
+        for message in messages {
+            // Failures of messages with an id are reported against that id.
+            let tracked = message
+                .id
+                .filter(|_| message.peer != SYSTEM_PEER)
+                .map(|id| (message.peer, id));
+            let logging_in = !session.is_logged_in(current_index);
+            let result = if logging_in {
+                login(session, registry, current_index, message).map(|index| current_index = index)
+            } else {
+                handle_message(session, current_index, message)
+            };
+            if let Err(err) = result {
+                let refused = logging_in && matches!(err, HandleMessageError::AuthFailed(_));
+                let msg = match err {
                     HandleMessageError::PeerNotFound(peer) => {
                         eprintln!("Peer not found: {}", peer);
-                        let msg = Message::encode(0, "Peer not found".as_bytes().to_vec().as_ref());
-                        write_message(&mut read_stream.lock().unwrap(), msg);
+                        SystemMessage::error(ErrorCode::PeerNotFound, "Peer not found")
+                    }
+                    HandleMessageError::RoomNotFound(room) => {
+                        eprintln!("Room not found: {}", room);
+                        SystemMessage::error(ErrorCode::RoomNotFound, "Room not found")
+                    }
+                    HandleMessageError::NotInRoom(room) => {
+                        eprintln!("Client {} is not in room {}", current_index, room);
+                        SystemMessage::error(ErrorCode::NotInRoom, "Not in room")
+                    }
+                    HandleMessageError::InvalidRoomName(name) => {
+                        eprintln!("Invalid room name: {}", name);
+                        SystemMessage::error(ErrorCode::InvalidRoomName, "Invalid room name")
+                    }
+                    HandleMessageError::TooManyRooms => {
+                        eprintln!("Room ids exhausted");
+                        SystemMessage::error(ErrorCode::ServerFull, "Too many rooms")
+                    }
+                    HandleMessageError::AuthFailed(reason) => {
+                        eprintln!("Login failed for client {}: {}", current_index, reason);
+                        SystemMessage::error(ErrorCode::AuthFailed, reason)
+                    }
+                    HandleMessageError::NameInUse => {
+                        eprintln!("Client {} logged in under a name in use", current_index);
+                        SystemMessage::error(ErrorCode::NameInUse, "Already logged in")
                     }
-                    HandleMessageError::SendChanError(send_error) => {
-                        eprintln!("Failed to send message: {:?}", send_error);
-                        let msg = Message::encode(
-                            0,
-                            "Failed to send message".as_bytes().to_vec().as_ref(),
-                        );
-                        write_message(&mut read_stream.lock().unwrap(), msg);
+                    HandleMessageError::NotLoggedIn => {
+                        eprintln!("Client {} sent a message before logging in", current_index);
+                        SystemMessage::error(ErrorCode::NotLoggedIn, "Log in first")
                     }
+                    HandleMessageError::RateLimited => {
+                        eprintln!("Client {} is broadcasting too fast", current_index);
+                        SystemMessage::error(ErrorCode::RateLimited, "Broadcasting too fast")
+                    }
+                    HandleMessageError::QueueFull(peer) => {
+                        eprintln!("Offline queue for {} is full", peer);
+                        SystemMessage::error(ErrorCode::QueueFull, "Offline queue full")
+                    }
+                    HandleMessageError::InvalidSystemMessage(parse_error) => {
+                        eprintln!("Invalid system message: {:?}", parse_error);
+                        SystemMessage::error(ErrorCode::InvalidMessage, "Invalid message")
+                    }
+                };
+                let msg = match (tracked, msg) {
+                    (Some((peer, id)), SystemMessage::Error { code, message }) => {
+                        SystemMessage::Failed {
+                            peer,
+                            id,
+                            code,
+                            message,
+                        }
+                    }
+                    (_, msg) => msg,
+                };
+                reply(session, current_index, msg);
+                // A failed login gets no second try on the same connection; anything sent
+                // after it goes with it.
+                if refused {
+                    session.flush(current_index);
+                    session.dead.push(current_index);
+                    return;
                 }
             }
         }
-    });
-    let write_stream = Arc::clone(&client_stream);
-    let h2 = thread::spawn(move || {
-        loop {
-            let internal_res = internal_rx.try_recv();
-            match internal_res {
-                Ok(_) => {
-                    println!(
-                        "Internal shutdown signal received, stopping write thread for client: {}",
-                        current_index
-                    );
-                    break;
+        // A client that keeps sending could hold this loop for a long time; whoever it
+        // overran shouldn't linger as a peer until it stops.
+        session.reap(registry);
+    }
+}
+
+fn reply(session: &mut GlobalState, index: PeerId, msg: SystemMessage) {
+    session.send_to(index, msg.to_frames());
+}
+
+pub enum HandleMessageError {
+    PeerNotFound(PeerId),
+    RoomNotFound(PeerId),
+    NotInRoom(PeerId),
+    InvalidRoomName(String),
+    TooManyRooms,
+    AuthFailed(&'static str),
+    NameInUse,
+    NotLoggedIn,
+    RateLimited,
+    QueueFull(PeerId),
+    InvalidSystemMessage(ParseError),
+}
+
+fn handle_message(
+    session: &mut GlobalState,
+    src: PeerId,
+    msg: Message,
+) -> Result<(), HandleMessageError> {
+    let peer = msg.peer;
+    if peer == SYSTEM_PEER {
+        return handle_system_message(session, src, &msg.content);
+    }
+    if is_room(peer) {
+        let members = match session.rooms.get(&peer) {
+            Some(room) if room.members.contains(&src) => room.members.clone(),
+            Some(_) => return Err(HandleMessageError::NotInRoom(peer)),
+            None => return Err(HandleMessageError::RoomNotFound(peer)),
+        };
+        if session.is_known(src) {
+            session.history.record(src, peer, &msg.content);
+        }
+        let (id, msg) = (
+            msg.id,
+            Message::encode_with_id(peer, Some(src), msg.id, &msg.content),
+        );
+        for member in members.into_iter().filter(|member| *member != src) {
+            session.send(member, Outbound::client(msg.clone(), src, id, false));
+        }
+        return Ok(());
+    }
+    if peer == BROADCAST_PEER {
+        let limiter = match session.users.get_mut(&src) {
+            Some(conn) => &mut conn.broadcasts,
+            None => return Ok(()),
+        };
+        if !limiter.try_take(Instant::now()) {
+            return Err(HandleMessageError::RateLimited);
+        }
+        if session.is_known(src) {
+            session.history.record(src, BROADCAST_PEER, &msg.content);
+        }
+        let content = Message::encode_with_id(BROADCAST_PEER, Some(src), msg.id, &msg.content);
+        broadcast_message(session, src, msg.id, content);
+        return Ok(());
+    }
+    let (id, content) = (msg.id, msg.content);
+    let msg = Outbound::client(
+        Message::encode_with_id(src, None, id, &content),
+        src,
+        id,
+        true,
+    );
+    if session.is_logged_in(peer) {
+        session.send(peer, msg);
+    } else if session.is_known(peer) {
+        // Users who have been here before get it when they come back.
+        session.queue_offline(peer, msg)?;
+        reply(session, src, SystemMessage::Queued { peer, id });
+    } else {
+        return Err(HandleMessageError::PeerNotFound(peer));
+    }
+    // History follows names, so sessions without one leave none.
+    if session.is_known(src) && session.is_known(peer) {
+        session.history.record(src, peer, &content);
+    }
+    Ok(())
+}
+
+fn handle_system_message(
+    session: &mut GlobalState,
+    src: PeerId,
+    content: &[u8],
+) -> Result<(), HandleMessageError> {
+    let msg = SystemMessage::decode(content).map_err(HandleMessageError::InvalidSystemMessage)?;
+    match msg {
+        SystemMessage::CreateRoom { name } => session.create_room(src, name),
+        SystemMessage::JoinRoom(room) => session.join_room(src, room),
+        SystemMessage::LeaveRoom(room) => session.leave_room(src, room),
+        SystemMessage::ListRooms => {
+            session.list_rooms(src);
+            Ok(())
+        }
+        SystemMessage::Login { .. } => Err(HandleMessageError::AuthFailed("Already logged in")),
+        SystemMessage::Ping => {
+            reply(session, src, SystemMessage::Pong);
+            Ok(())
+        }
+        // Arriving at all is the point; last_seen is already updated.
+        SystemMessage::Pong => Ok(()),
+        SystemMessage::HistoryQuery {
+            peer,
+            limit,
+            before,
+        } => session.send_history(src, peer, limit, before),
+        // Receipts for senders who have since left are dropped.
+        SystemMessage::Read { peer, id } => {
+            if session.is_logged_in(peer) {
+                reply(session, peer, SystemMessage::Read { peer: src, id });
+            }
+            Ok(())
+        }
+        SystemMessage::PublishKey(key) => {
+            session.keys.insert(src, key);
+            session.broadcast_except(src, SystemMessage::PeerKey { peer: src, key });
+            Ok(())
+        }
+        // Everything else only ever flows from the server to clients
+        _ => Err(HandleMessageError::InvalidSystemMessage(
+            ParseError::UnknownSystemMessage,
+        )),
+    }
+}
+
+// Until a client logs in, the only thing it may send is its LOGIN.
+fn login(
+    session: &mut GlobalState,
+    registry: &Registry,
+    src: PeerId,
+    msg: Message,
+) -> Result<PeerId, HandleMessageError> {
+    if msg.peer != SYSTEM_PEER {
+        return Err(HandleMessageError::NotLoggedIn);
+    }
+    let msg =
+        SystemMessage::decode(&msg.content).map_err(HandleMessageError::InvalidSystemMessage)?;
+    match msg {
+        SystemMessage::Login { username, password } => {
+            session.login(registry, src, username, &password)
+        }
+        // Heartbeats may cross with a slow login.
+        SystemMessage::Ping => {
+            reply(session, src, SystemMessage::Pong);
+            Ok(src)
+        }
+        SystemMessage::Pong => Ok(src),
+        _ => Err(HandleMessageError::NotLoggedIn),
+    }
+}
+
+fn broadcast_message(session: &mut GlobalState, src: PeerId, id: Option<u64>, content: RawMessage) {
+    let users = session.logged_in().into_iter().map(|(index, _)| index);
+    let users = users.filter(|index| *index != src).collect::<Vec<PeerId>>();
+    for index in users {
+        session.send(index, Outbound::client(content.clone(), src, id, false));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::FileAuthenticator,
+        shared::FrameReader,
+        tls::{ClientTls, Trust, tests::generate_certs},
+    };
+    use std::{
+        net,
+        thread::{self, JoinHandle},
+    };
+
+    fn start(config: ServerConfig) -> (SocketAddr, ShutdownHandle, JoinHandle<io::Result<()>>) {
+        spawn(Server::bind(config).unwrap())
+    }
+
+    // A server where these names have accounts with empty passwords, so they keep their ids.
+    fn start_with_accounts(
+        config: ServerConfig,
+        names: &[&str],
+    ) -> (SocketAddr, ShutdownHandle, JoinHandle<io::Result<()>>) {
+        let users = names
+            .iter()
+            .map(|name| FileAuthenticator::entry_with_rounds(name, "", 1).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let auth = FileAuthenticator::parse(&users).unwrap();
+        spawn(Server::bind(config).unwrap().with_authenticator(auth))
+    }
+
+    fn spawn(server: Server) -> (SocketAddr, ShutdownHandle, JoinHandle<io::Result<()>>) {
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        (addr, handle, thread::spawn(move || server.run()))
+    }
+
+    fn ephemeral() -> ServerConfig {
+        ServerConfig {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            ..ServerConfig::default()
+        }
+    }
+
+    struct TestClient {
+        stream: Stream<net::TcpStream>,
+        reader: FrameReader,
+        inbox: VecDeque<Message>,
+    }
+
+    impl TestClient {
+        fn connect(addr: SocketAddr) -> Self {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            Self::wrap(Stream::Plain(stream))
+        }
+
+        fn connect_tls(addr: SocketAddr, tls: &ClientTls) -> Self {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            let config = tls::client_config(tls).unwrap();
+            let mut stream = Stream::client(stream, config, &tls.server_name).unwrap();
+            // Finish the handshake before reads start timing out
+            stream.flush().unwrap();
+            Self::wrap(stream)
+        }
+
+        fn wrap(stream: Stream<net::TcpStream>) -> Self {
+            stream
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+            TestClient {
+                stream,
+                reader: FrameReader::new(),
+                inbox: VecDeque::new(),
+            }
+        }
+
+        fn login(addr: SocketAddr, name: &str) -> Self {
+            let mut client = Self::connect(addr);
+            client.send_system(SystemMessage::Login {
+                username: name.to_string(),
+                password: String::new(),
+            });
+            client
+        }
+
+        fn send(&mut self, peer: PeerId, content: &[u8]) {
+            for part in Message::encode(peer, content) {
+                self.stream.write_all(&part).unwrap();
+            }
+        }
+
+        fn send_tracked(&mut self, peer: PeerId, id: u64, content: &[u8]) {
+            for part in Message::encode_with_id(peer, None, Some(id), content) {
+                self.stream.write_all(&part).unwrap();
+            }
+        }
+
+        fn send_system(&mut self, msg: SystemMessage) {
+            self.send(SYSTEM_PEER, &msg.encode());
+        }
+
+        // Waits for the first message matching `pred`, skipping the ones before it.
+        fn expect(&mut self, pred: impl Fn(&Message) -> bool) -> Message {
+            for _ in 0..40 {
+                while let Some(msg) = self.inbox.pop_front() {
+                    if pred(&msg) {
+                        return msg;
+                    }
                 }
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => {
-                    println!(
-                        "Internal channel disconnected, stopping write thread for client: {}",
-                        current_index
-                    );
-                    break; // Channel disconnected, stop processing
+                match self.reader.read_from(&mut self.stream) {
+                    Ok(messages) => self.inbox.extend(messages),
+                    Err(ExtractError::NotReady) => {}
+                    Err(e) => panic!("read failed: {:?}", e),
                 }
+            }
+            panic!("no matching message arrived");
+        }
+
+        // Whether the server closes the connection, reading whatever arrives before that.
+        fn closed(&mut self) -> bool {
+            (0..40).any(|_| match self.reader.read_from(&mut self.stream) {
+                Ok(messages) => {
+                    self.inbox.extend(messages);
+                    false
+                }
+                Err(ExtractError::NotReady) => false,
+                Err(_) => true,
+            })
+        }
+
+        fn expect_system(&mut self, expected: SystemMessage) {
+            self.expect(|m| {
+                m.peer == SYSTEM_PEER && SystemMessage::decode(&m.content).as_ref() == Ok(&expected)
+            });
+        }
+    }
+
+    // Peer list for clients that logged in with these names in order, so got ids from 1 up.
+    fn peers(names: &[&str]) -> SystemMessage {
+        let peers = names.iter().enumerate();
+        SystemMessage::Peers(
+            peers
+                .map(|(i, name)| (i as PeerId + 1, name.to_string()))
+                .collect(),
+        )
+    }
+
+    fn joined(peer: PeerId, name: &str) -> SystemMessage {
+        SystemMessage::PeerJoined {
+            peer,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_routes_between_clients() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(SystemMessage::ServerInfo {
+            protocol: VERSION,
+            peer: 1,
+        });
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        a.expect_system(joined(2, "b"));
+
+        a.send(2, b"hello ending in 1");
+        let msg = b.expect(|m| m.peer != SYSTEM_PEER);
+        assert_eq!(msg, Message::new(1, b"hello ending in 1".to_vec(), false));
+
+        b.send(7, b"nobody home");
+        b.expect_system(SystemMessage::error(
+            ErrorCode::PeerNotFound,
+            "Peer not found",
+        ));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_peer_changes_are_incremental() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        let mut c = TestClient::login(addr, "c");
+        c.expect_system(peers(&["a", "b", "c"]));
+
+        a.expect_system(joined(2, "b"));
+        a.expect_system(joined(3, "c"));
+        b.expect_system(joined(3, "c"));
+
+        drop(c);
+        a.expect_system(SystemMessage::PeerLeft(3));
+        b.expect_system(SystemMessage::PeerLeft(3));
+        // No full list is re-sent after the initial snapshot
+        assert!(a.inbox.iter().all(|m| {
+            !matches!(
+                SystemMessage::decode(&m.content),
+                Ok(SystemMessage::Peers(_))
+            )
+        }));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rooms() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        let mut c = TestClient::login(addr, "c");
+        c.expect_system(peers(&["a", "b", "c"]));
+
+        let room = ROOM_BIT | 1;
+        a.send_system(SystemMessage::CreateRoom {
+            name: "general".to_string(),
+        });
+        a.expect_system(SystemMessage::RoomCreated {
+            room,
+            name: "general".to_string(),
+        });
+        a.expect_system(SystemMessage::RoomJoined { room, peer: 1 });
+
+        b.send_system(SystemMessage::ListRooms);
+        b.expect_system(SystemMessage::Rooms(vec![(room, "general".to_string())]));
+        b.send_system(SystemMessage::JoinRoom(room));
+        b.expect_system(SystemMessage::RoomJoined { room, peer: 2 });
+        a.expect_system(SystemMessage::RoomJoined { room, peer: 2 });
+
+        // Outsiders can't post, members reach everyone else in the room
+        c.send(room, b"let me in");
+        c.expect_system(SystemMessage::error(ErrorCode::NotInRoom, "Not in room"));
+        a.send(room, b"hi room");
+        let msg = b.expect(|m| m.peer == room);
+        assert_eq!((msg.sender(), msg.content), (1, b"hi room".to_vec()));
+
+        // Leaving, including by disconnecting, updates membership
+        drop(b);
+        a.expect_system(SystemMessage::RoomLeft { room, peer: 2 });
+        a.send_system(SystemMessage::LeaveRoom(room));
+        a.expect_system(SystemMessage::RoomLeft { room, peer: 1 });
+        a.send_system(SystemMessage::ListRooms);
+        a.expect_system(SystemMessage::Rooms(vec![]));
+        a.send_system(SystemMessage::JoinRoom(room));
+        a.expect_system(SystemMessage::error(
+            ErrorCode::RoomNotFound,
+            "Room not found",
+        ));
+        a.send_system(SystemMessage::CreateRoom {
+            name: "no,commas".to_string(),
+        });
+        a.expect_system(SystemMessage::error(
+            ErrorCode::InvalidRoomName,
+            "Invalid room name",
+        ));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_room_history() {
+        let (addr, handle, server) = start_with_accounts(ephemeral(), &["a", "b", "c"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        let mut c = TestClient::login(addr, "c");
+        c.expect_system(peers(&["a", "b", "c"]));
+
+        let room = ROOM_BIT | 1;
+        a.send_system(SystemMessage::CreateRoom {
+            name: "general".to_string(),
+        });
+        a.expect_system(SystemMessage::RoomJoined { room, peer: 1 });
+        a.send(room, b"before b");
+        b.send_system(SystemMessage::JoinRoom(room));
+        a.expect_system(SystemMessage::RoomJoined { room, peer: 2 });
+        b.send(room, b"hi room");
+        a.expect(|m| m.content == b"hi room");
+
+        // Members see what was said before they joined too
+        let query = SystemMessage::HistoryQuery {
+            peer: room,
+            limit: 10,
+            before: None,
+        };
+        assert_eq!(
+            history(&mut b, query.clone()),
+            vec![b"before b".to_vec(), b"hi room".to_vec()]
+        );
+        c.send_system(query);
+        c.expect_system(SystemMessage::error(ErrorCode::NotInRoom, "Not in room"));
+
+        // A new room under the same id starts with a clean slate
+        a.send_system(SystemMessage::LeaveRoom(room));
+        b.send_system(SystemMessage::LeaveRoom(room));
+        b.expect_system(SystemMessage::RoomLeft { room, peer: 2 });
+        c.send_system(SystemMessage::CreateRoom {
+            name: "again".to_string(),
+        });
+        c.expect_system(SystemMessage::RoomJoined { room, peer: 3 });
+        let query = SystemMessage::HistoryQuery {
+            peer: room,
+            limit: 10,
+            before: None,
+        };
+        assert!(history(&mut c, query).is_empty());
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_servers_run_side_by_side() {
+        let (addr_a, handle_a, server_a) = start(ephemeral());
+        let (addr_b, handle_b, server_b) = start(ephemeral());
+        assert_ne!(addr_a, addr_b);
+
+        // Each server hands out ids independently
+        TestClient::login(addr_a, "a").expect_system(peers(&["a"]));
+        TestClient::login(addr_b, "a").expect_system(peers(&["a"]));
+
+        handle_a.shutdown();
+        handle_b.shutdown();
+        server_a.join().unwrap().unwrap();
+        server_b.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_broadcast_reaches_everyone_but_sender() {
+        let config = ServerConfig {
+            broadcast_limit: BroadcastLimit {
+                burst: 1,
+                interval: Duration::from_secs(60),
+            },
+            ..ephemeral()
+        };
+        let (addr, handle, server) = start(config);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        let mut c = TestClient::login(addr, "c");
+        c.expect_system(peers(&["a", "b", "c"]));
+
+        a.send(BROADCAST_PEER, b"hello all");
+        for client in [&mut b, &mut c] {
+            let msg = client.expect(|m| m.peer == BROADCAST_PEER);
+            assert_eq!(msg.sender(), 1);
+            assert_eq!(msg.content, b"hello all");
+        }
+
+        a.send(BROADCAST_PEER, b"again");
+        a.expect_system(SystemMessage::error(
+            ErrorCode::RateLimited,
+            "Broadcasting too fast",
+        ));
+        a.send(2, b"direct still works");
+        b.expect(|m| m.content == b"direct still works");
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rate_limiter_refills() {
+        let limit = BroadcastLimit {
+            burst: 2,
+            interval: Duration::from_secs(1),
+        };
+        let mut limiter = RateLimiter::new(limit);
+        let start = limiter.refilled;
+        assert!(limiter.try_take(start));
+        assert!(limiter.try_take(start));
+        assert!(!limiter.try_take(start));
+        assert!(limiter.try_take(start + Duration::from_millis(1500)));
+        assert!(!limiter.try_take(start + Duration::from_millis(1900)));
+        assert!(limiter.try_take(start + Duration::from_secs(2)));
+        assert!(limiter.try_take(start + Duration::from_secs(10)));
+        assert!(limiter.try_take(start + Duration::from_secs(10)));
+        assert!(!limiter.try_take(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_rejects_past_max_peers() {
+        let (addr, handle, server) = start(ServerConfig {
+            max_peers: 1,
+            ..ephemeral()
+        });
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::connect(addr);
+        b.expect_system(SystemMessage::error(ErrorCode::ServerFull, "Server full"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_notifies_and_closes_clients() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+
+        handle.shutdown();
+        a.expect_system(SystemMessage::Shutdown {
+            reason: "Server is shutting down".to_string(),
+        });
+        server.join().unwrap().unwrap();
+        assert!(matches!(
+            a.reader.read_from(&mut a.stream),
+            Err(ExtractError::Closed)
+        ));
+        assert!(net::TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_nothing_is_routed_before_login() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::connect(addr);
+        b.send(1, b"sneaky");
+        b.expect_system(SystemMessage::error(ErrorCode::NotLoggedIn, "Log in first"));
+        b.send_system(SystemMessage::ListRooms);
+        b.expect_system(SystemMessage::error(ErrorCode::NotLoggedIn, "Log in first"));
+        a.send(2, b"not yet");
+        a.expect_system(SystemMessage::error(
+            ErrorCode::PeerNotFound,
+            "Peer not found",
+        ));
+        b.send_system(SystemMessage::Login {
+            username: "a".to_string(),
+            password: String::new(),
+        });
+        b.expect_system(SystemMessage::error(
+            ErrorCode::NameInUse,
+            "Already logged in",
+        ));
+        b.send_system(SystemMessage::Login {
+            username: "b".to_string(),
+            password: String::new(),
+        });
+        b.expect_system(peers(&["a", "b"]));
+        a.expect_system(joined(2, "b"));
+        assert!(a.inbox.iter().all(|m| m.content != b"sneaky"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_returning_users_keep_their_id() {
+        let (addr, handle, server) = start_with_accounts(ephemeral(), &["a", "b", "c"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+        drop(b);
+        a.expect_system(SystemMessage::PeerLeft(2));
+
+        // The newcomer is handed id 3 first, then moved back to 2 once it says who it is
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(SystemMessage::ServerInfo {
+            protocol: VERSION,
+            peer: 2,
+        });
+        a.expect_system(joined(2, "b"));
+        a.send(2, b"welcome back");
+        b.expect(|m| m.content == b"welcome back");
+        let c = TestClient::login(addr, "c");
+        drop(c);
+        a.expect_system(joined(3, "c"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_takes_over_from_stale_connection() {
+        let (addr, handle, server) = start_with_accounts(ephemeral(), &["a", "b"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut old = TestClient::login(addr, "b");
+        old.expect_system(peers(&["a", "b"]));
+        a.expect_system(joined(2, "b"));
+
+        // The old socket is still open, as after a network drop nobody has noticed yet
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(SystemMessage::ServerInfo {
+            protocol: VERSION,
+            peer: 2,
+        });
+        a.expect_system(SystemMessage::PeerLeft(2));
+        a.expect_system(joined(2, "b"));
+        a.send(2, b"still there?");
+        b.expect(|m| m.content == b"still there?");
+        assert!(old.closed(), "old connection still open");
+        assert!(old.inbox.iter().all(|m| m.content != b"still there?"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_queues_for_offline_users() {
+        let config = ServerConfig {
+            offline_limit: OfflineLimit {
+                quota: 2,
+                expiry: Duration::from_secs(60),
+            },
+            ..ephemeral()
+        };
+        let (addr, handle, server) = start_with_accounts(config, &["a", "b"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+        drop(b);
+        a.expect_system(SystemMessage::PeerLeft(2));
+
+        a.send(2, b"first");
+        a.expect_system(SystemMessage::Queued { peer: 2, id: None });
+        a.send(2, b"second");
+        a.expect_system(SystemMessage::Queued { peer: 2, id: None });
+        a.send(2, b"third");
+        a.expect_system(SystemMessage::error(
+            ErrorCode::QueueFull,
+            "Offline queue full",
+        ));
+
+        // Delivered in order once they're back, and only once
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        assert_eq!(b.expect(|m| m.peer == 1).content, b"first");
+        assert_eq!(b.expect(|m| m.peer == 1).content, b"second");
+        a.send(2, b"live");
+        assert_eq!(b.expect(|m| m.peer == 1).content, b"live");
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_drops_expired_offline_messages() {
+        let config = ServerConfig {
+            offline_limit: OfflineLimit {
+                quota: 10,
+                expiry: Duration::ZERO,
+            },
+            ..ephemeral()
+        };
+        let (addr, handle, server) = start_with_accounts(config, &["a", "b"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+        drop(b);
+        a.expect_system(SystemMessage::PeerLeft(2));
+
+        a.send(2, b"stale");
+        a.expect_system(SystemMessage::Queued { peer: 2, id: None });
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        a.send(2, b"fresh");
+        assert_eq!(b.expect(|m| m.peer == 1).content, b"fresh");
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    // The content of the HistoryEntry messages `client` gets back for `query`.
+    fn history(client: &mut TestClient, query: SystemMessage) -> Vec<Vec<u8>> {
+        let SystemMessage::HistoryQuery { peer, .. } = query else {
+            panic!("not a history query");
+        };
+        client.send_system(query);
+        let mut page = vec![];
+        loop {
+            let msg = client.expect(|m| m.peer == SYSTEM_PEER);
+            match SystemMessage::decode(&msg.content) {
+                Ok(SystemMessage::HistoryEntry { content, .. }) => page.push(content),
+                Ok(SystemMessage::HistoryEnd(end)) if end == peer => return page,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_history_survives_restart() {
+        let path =
+            std::env::temp_dir().join(format!("md-redis-server-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = ServerConfig {
+            history_path: Some(path.clone()),
+            ..ephemeral()
+        };
+        let (addr, handle, server) = start_with_accounts(config.clone(), &["a", "b"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+        a.send(2, b"one");
+        b.expect(|m| m.content == b"one");
+        b.send(1, b"two");
+        a.expect(|m| m.content == b"two");
+        a.send(2, b"three");
+        b.expect(|m| m.content == b"three");
+        a.send(BROADCAST_PEER, b"everyone");
+        b.expect(|m| m.content == b"everyone");
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+
+        // "b" logs in first this time but keeps id 2
+        let (addr, handle, server) = start_with_accounts(config, &["a", "b"]);
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(SystemMessage::ServerInfo {
+            protocol: VERSION,
+            peer: 2,
+        });
+        let query = |limit, before| SystemMessage::HistoryQuery {
+            peer: 1,
+            limit,
+            before,
+        };
+        assert_eq!(
+            history(&mut b, query(10, None)),
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+        assert_eq!(
+            history(&mut b, query(2, None)),
+            vec![b"two".to_vec(), b"three".to_vec()]
+        );
+        assert_eq!(history(&mut b, query(10, Some(2))), vec![b"one".to_vec()]);
+        let broadcasts = SystemMessage::HistoryQuery {
+            peer: BROADCAST_PEER,
+            limit: 10,
+            before: None,
+        };
+        assert_eq!(history(&mut b, broadcasts), vec![b"everyone".to_vec()]);
+        // "a" is still known, so messages to them are queued rather than refused
+        b.send(1, b"four");
+        b.expect_system(SystemMessage::Queued { peer: 1, id: None });
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unproven_names_start_from_scratch() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+        a.send(2, b"for b only");
+        b.expect(|m| m.content == b"for b only");
+        drop(b);
+        a.expect_system(SystemMessage::PeerLeft(2));
+
+        // Nothing is kept for whoever says they are "b" next
+        a.send(2, b"later");
+        a.expect_system(SystemMessage::error(
+            ErrorCode::PeerNotFound,
+            "Peer not found",
+        ));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(peers(&["a", "b"]));
+        let query = SystemMessage::HistoryQuery {
+            peer: 1,
+            limit: 10,
+            before: None,
+        };
+        assert!(history(&mut b, query).is_empty());
+        assert!(b.inbox.iter().all(|m| m.content != b"later"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_evicts_clients_that_miss_heartbeats() {
+        let config = ServerConfig {
+            heartbeat: Heartbeat {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(300),
+            },
+            ..ephemeral()
+        };
+        let (addr, handle, server) = start(config);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let _b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+
+        // "a" keeps talking while "b" has gone silent without closing its socket
+        a.expect_system(SystemMessage::Ping);
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            a.send_system(SystemMessage::Ping);
+            let msg = a.expect(|m| m.peer == SYSTEM_PEER);
+            if SystemMessage::decode(&msg.content) == Ok(SystemMessage::PeerLeft(2)) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "silent client was never dropped");
+        }
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_acks_and_receipts() {
+        let (addr, handle, server) = start_with_accounts(ephemeral(), &["a", "b"]);
+        let mut a = TestClient::login(addr, "a");
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        a.expect_system(joined(2, "b"));
+
+        a.send_tracked(2, 41, b"tracked");
+        let msg = b.expect(|m| m.content == b"tracked");
+        assert_eq!((msg.peer, msg.id), (1, Some(41)));
+        a.expect_system(SystemMessage::Delivered { peer: 2, id: 41 });
+        b.send_system(SystemMessage::Read { peer: 1, id: 41 });
+        a.expect_system(SystemMessage::Read { peer: 2, id: 41 });
+
+        a.send_tracked(7, 42, b"nobody home");
+        a.expect_system(SystemMessage::Failed {
+            peer: 7,
+            id: 42,
+            code: ErrorCode::PeerNotFound,
+            message: "Peer not found".to_string(),
+        });
+
+        // Queued messages are acked once they finally go out
+        drop(b);
+        a.expect_system(SystemMessage::PeerLeft(2));
+        a.send_tracked(2, 43, b"later");
+        a.expect_system(SystemMessage::Queued {
+            peer: 2,
+            id: Some(43),
+        });
+        let mut b = TestClient::login(addr, "b");
+        b.expect(|m| m.content == b"later");
+        a.expect_system(SystemMessage::Delivered { peer: 2, id: 43 });
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_slow_consumers() {
+        for policy in [
+            SlowConsumerPolicy::DropOldest,
+            SlowConsumerPolicy::Disconnect,
+        ] {
+            let config = ServerConfig {
+                outbound_limit: OutboundLimit {
+                    capacity: 4,
+                    policy,
+                },
+                // "b" would hold up shutdown for the whole drain otherwise
+                drain_timeout: Duration::from_millis(100),
+                ..ephemeral()
             };
-            let res = rx.try_recv();
-            match res {
-                Ok(msg) => {
-                    write_message(&mut write_stream.lock().unwrap(), msg);
+            let (addr, handle, server) = start(config);
+            let mut a = TestClient::login(addr, "a");
+            a.expect_system(peers(&["a"]));
+            // "b" never reads, so once the socket buffers fill its queue does too
+            let _b = TestClient::login(addr, "b");
+            a.expect_system(joined(2, "b"));
+            let content = vec![b'x'; 64 * 1024];
+            a.stream
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_millis(1)))
+                .unwrap();
+            let mut outcome = None;
+            for id in 0..1000 {
+                a.send_tracked(2, id, &content);
+                match a.reader.read_from(&mut a.stream) {
+                    Ok(messages) => a.inbox.extend(messages),
+                    Err(ExtractError::NotReady) => {}
+                    Err(e) => panic!("read failed: {:?}", e),
                 }
-                Err(ref e) if *e == TryRecvError::Empty => {
-                    // continue;
+                outcome = a
+                    .inbox
+                    .iter()
+                    .filter(|m| m.peer == SYSTEM_PEER)
+                    .filter_map(|m| SystemMessage::decode(&m.content).ok())
+                    .find(|m| {
+                        matches!(
+                            m,
+                            SystemMessage::Dropped { .. } | SystemMessage::PeerLeft(_)
+                        )
+                    });
+                if outcome.is_some() {
+                    break;
                 }
-                Err(e) => {
-                    eprintln!("Error receiving message: {:?}", e);
-                    // continue;
+            }
+            match outcome.expect("the server never gave up on the slow client") {
+                SystemMessage::Dropped { peer, id } => {
+                    assert_eq!(policy, SlowConsumerPolicy::DropOldest);
+                    assert_eq!(peer, 2);
+                    assert!(id.is_some());
+                }
+                msg => {
+                    assert_eq!(policy, SlowConsumerPolicy::Disconnect);
+                    assert_eq!(msg, SystemMessage::PeerLeft(2));
                 }
             }
+
+            handle.shutdown();
+            server.join().unwrap().unwrap();
         }
-    });
-    h1.join().expect("Failed to join read thread");
-    h2.join().expect("Failed to join write thread");
-    {
-        let mut session = SESSION.get().expect("Not Initialized").lock().unwrap();
-        GlobalState::remove_user(&mut session, current_index);
     }
-    return;
-}
 
-pub enum HandleMessageError {
-    PeerNotFound(usize),
-    SendChanError(SendError<RawMessage>),
-}
-
-pub fn handle_message(src: usize, msg: Message) -> Result<(), HandleMessageError> {
-    // let (_add_user, _remove_user, mut get_user) = session_maker_chan();
-    let session = SESSION
-        .get()
-        .expect("Global state not initialized")
-        .lock()
-        .unwrap();
-    let peer = msg.clone().peer;
-    let peer_chan = session.get_user(peer);
-    if peer_chan.is_none() {
-        return Err(HandleMessageError::PeerNotFound(peer));
+    #[test]
+    fn test_checks_credentials() {
+        let users = FileAuthenticator::entry_with_rounds("alice", "secret", 1).unwrap();
+        let auth = FileAuthenticator::parse(&users).unwrap();
+        let (addr, handle, server) =
+            spawn(Server::bind(ephemeral()).unwrap().with_authenticator(auth));
+
+        let mut a = TestClient::connect(addr);
+        a.send_system(SystemMessage::Login {
+            username: "alice".to_string(),
+            password: "wrong".to_string(),
+        });
+        a.expect_system(SystemMessage::error(
+            ErrorCode::AuthFailed,
+            "Bad username or password",
+        ));
+        assert!(a.closed());
+
+        // Even the right password has to wait a while after a wrong one
+        let login = || SystemMessage::Login {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        };
+        let mut b = TestClient::connect(addr);
+        b.send_system(login());
+        b.expect_system(SystemMessage::error(
+            ErrorCode::AuthFailed,
+            "Too many attempts, try again later",
+        ));
+        assert!(b.closed());
+        thread::sleep(ServerConfig::default().login_delay);
+        let mut c = TestClient::connect(addr);
+        c.send_system(login());
+        c.expect_system(SystemMessage::Peers(vec![(1, "alice".to_string())]));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
     }
-    let peer_chan = peer_chan.unwrap();
 
-    let msg = Message::encode(src, msg.content.as_ref());
+    #[test]
+    fn test_relays_identity_keys() {
+        let (addr, handle, server) = start(ephemeral());
+        let mut a = TestClient::login(addr, "a");
+        a.send_system(SystemMessage::PublishKey([1; 32]));
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::login(addr, "b");
+        b.expect_system(SystemMessage::PeerKey {
+            peer: 1,
+            key: [1; 32],
+        });
+        b.send_system(SystemMessage::PublishKey([2; 32]));
+        a.expect_system(SystemMessage::PeerKey {
+            peer: 2,
+            key: [2; 32],
+        });
+
+        // Sealed payloads pass through untouched
+        let sealed = [&[0xFF, b'E', b'2', b'E'][..], &[0x5A; 40]].concat();
+        a.send(2, &sealed);
+        assert_eq!(b.expect(|m| m.peer == 1).content, sealed);
 
-    let res = peer_chan.send(msg);
-    if res.is_err() {
-        return Err(HandleMessageError::SendChanError(res.unwrap_err()));
+        handle.shutdown();
+        server.join().unwrap().unwrap();
     }
-    Ok(())
-}
 
-fn broadcast_message(users: Vec<Sender<RawMessage>>, content: Vec<Vec<u8>>) {
-    for sender in users.iter() {
-        sender.send(content.clone()).unwrap_or_else(|e| {
-            eprintln!("Failed to send message to peer: {:?}", e);
+    #[test]
+    fn test_tls() {
+        let certs = generate_certs();
+        let (addr, handle, server) = start(ServerConfig {
+            tls: Some(certs.server.clone()),
+            ..ephemeral()
+        });
+        let tls = ClientTls {
+            trust: Trust::CaBundle(certs.ca.clone()),
+            server_name: "localhost".to_string(),
+        };
+        let mut a = TestClient::connect_tls(addr, &tls);
+        a.send_system(SystemMessage::Login {
+            username: "a".to_string(),
+            password: String::new(),
+        });
+        a.expect_system(peers(&["a"]));
+        let mut b = TestClient::connect_tls(addr, &tls);
+        b.send_system(SystemMessage::Login {
+            username: "b".to_string(),
+            password: String::new(),
+        });
+        a.expect_system(joined(2, "b"));
+        a.send(2, &vec![b'x'; 3000]);
+        assert_eq!(b.expect(|m| m.peer == 1).content, vec![b'x'; 3000]);
+
+        handle.shutdown();
+        a.expect_system(SystemMessage::Shutdown {
+            reason: "Server is shutting down".to_string(),
         });
+        server.join().unwrap().unwrap();
+        assert!(matches!(
+            a.reader.read_from(&mut a.stream),
+            Err(ExtractError::Closed)
+        ));
+    }
+
+    // A connection over loopback for tests on GlobalState itself, with the client's end.
+    fn loopback() -> (Connection, TestClient) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let stream = Stream::Plain(TcpStream::from_std(server));
+        let conn = Connection::new(stream, ServerConfig::default().broadcast_limit);
+        (conn, TestClient::wrap(Stream::Plain(client)))
+    }
+
+    #[test]
+    fn test_sender_hears_when_legacy_client_cannot_take_it() {
+        let mut state = GlobalState::new(&ServerConfig::default(), None, History::in_memory());
+        let (mut old, _old_end) = loopback();
+        old.format = WireFormat::Legacy;
+        let (sender, mut sender_end) = loopback();
+        state.users.insert(1, old);
+        // Too wide for the four digits the legacy format has for the sender
+        state.users.insert(10000, sender);
+
+        for id in [Some(5), None] {
+            let msg = Message::encode_with_id(10000, None, id, b"hi");
+            assert!(!state.send(1, Outbound::client(msg, 10000, id, true)));
+        }
+        sender_end.expect_system(SystemMessage::Failed {
+            peer: 1,
+            id: 5,
+            code: ErrorCode::InvalidMessage,
+            message: "Recipient can't receive it".to_string(),
+        });
+        sender_end.expect_system(SystemMessage::error(
+            ErrorCode::InvalidMessage,
+            "Recipient can't receive it",
+        ));
+        assert!(state.users[&1].outbound.is_empty());
+    }
+
+    #[test]
+    fn test_ids_are_recycled() {
+        let mut ids = IdAllocator::new(PeerId::MAX);
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(3));
+        ids.release(2);
+        ids.release(1);
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(4));
+    }
+
+    #[test]
+    fn test_rooms_run_out() {
+        let mut state = GlobalState::new(&ServerConfig::default(), None, History::in_memory());
+        state.room_ids = IdAllocator::new(0);
+        assert!(matches!(
+            state.create_room(1, "general".to_string()),
+            Err(HandleMessageError::TooManyRooms)
+        ));
+    }
+
+    #[test]
+    fn test_ids_run_out_instead_of_wrapping() {
+        let mut ids = IdAllocator::new(2);
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(2));
+        assert!(ids.is_exhausted());
+        assert_eq!(ids.allocate(), None);
+        ids.release(SYSTEM_PEER);
+        assert_eq!(ids.allocate(), None);
+        ids.release(2);
+        assert_eq!(ids.allocate(), Some(2));
     }
 }
@@ -1,19 +1,61 @@
 use std::{
+    collections::VecDeque,
     io::{ErrorKind, Read, Write},
-    net::TcpStream,
+    time::Duration,
 };
 
-use crate::parser::{Message, ParseError};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
 
-pub fn write_message(stream: &mut TcpStream, msg: Vec<Vec<u8>>) {
+use crate::parser::{
+    Frame, HEADER_LEN, MAX_MESSAGE, MAX_PAYLOAD, Message, ParseError, WireFormat, legacy,
+};
+
+pub fn write_message<W: Write>(stream: &mut W, msg: Vec<Vec<u8>>) -> std::io::Result<()> {
     for part in msg {
-        if let Err(e) = stream.write_all(&part) {
-            eprintln!("Failed to write message to client: {}", e);
-            break;
+        stream.write_all(&part)?;
+    }
+    // A TLS stream may still be holding the last record.
+    stream.flush()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Both ends ping a connection that has been quiet for `interval`, and give up on it once
+// nothing at all has arrived for `timeout`. A zero interval turns heartbeats off.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
         }
     }
 }
 
+impl Heartbeat {
+    pub fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+}
+
 #[derive(Debug)]
 pub enum ExtractError {
     InvalidMessage(ParseError),
@@ -22,48 +64,413 @@ pub enum ExtractError {
     Closed,
 }
 
-pub fn extract_message(stream: &mut TcpStream) -> Result<Message, ExtractError> {
-    let mut buff = [0; 1029];
-    let mut final_msg = Message::new(0, vec![], false);
-    loop {
-        let bytes_read = stream.read(&mut buff);
-        match bytes_read {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
-                    return Err(ExtractError::Closed);
-                }
-                let msg = Message::parse(buff[..bytes_read].to_vec().as_ref());
-                match msg {
-                    Ok(msg) => {
-                        final_msg.peer = msg.peer;
-                        final_msg.content.extend_from_slice(&msg.content);
-                        if !msg.has_more {
-                            // for r in res.iter() {
-                            //     final_msg.content.extend_from_slice(&r.content);
-                            // }
-                            return Ok(final_msg);
-                        }
-                        buff = [0; 1029]; // Reset buffer for next read
-                    }
-                    Err(err) => {
-                        return Err(ExtractError::InvalidMessage(err));
-                    }
-                }
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                return Err(ExtractError::NotReady);
-            }
+// Per-connection reassembler: bytes that don't make up a whole frame yet stay
+// buffered until the next read, and chunked messages are joined back together.
+pub struct FrameReader {
+    buf: BytesMut,
+    format: Option<WireFormat>,
+    chunks: Chunks,
+    // Decoded but not handed out yet; a rejected message sits among them as its error, so the
+    // ones after it aren't lost.
+    ready: VecDeque<Result<Message, ParseError>>,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        FrameReader {
+            buf: BytesMut::with_capacity(HEADER_LEN + MAX_PAYLOAD),
+            format: None,
+            chunks: Chunks::default(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    // The format the peer speaks, known once its first bytes have arrived.
+    pub fn format(&self) -> Option<WireFormat> {
+        self.format
+    }
+
+    pub fn read_from<R: Read>(&mut self, stream: &mut R) -> Result<Vec<Message>, ExtractError> {
+        // What an earlier read left over comes before anything new
+        if !self.ready.is_empty() {
+            return self.next_batch().map_err(ExtractError::InvalidMessage);
+        }
+        let mut buff = [0; 4096];
+        match stream.read(&mut buff) {
+            Ok(0) => Err(ExtractError::Closed),
+            Ok(bytes_read) => self
+                .feed(&buff[..bytes_read])
+                .map_err(ExtractError::InvalidMessage),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Err(ExtractError::NotReady),
             Err(ref e)
                 if matches!(
                     e.kind(),
                     ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
                 ) =>
             {
-                return Err(ExtractError::Closed);
+                Err(ExtractError::Closed)
             }
-            Err(e) => {
-                return Err(ExtractError::IOError(e));
+            Err(e) => Err(ExtractError::IOError(e)),
+        }
+    }
+
+    // Hands out the messages decoded so far up to the next rejected one, or that rejection if
+    // it is first in line.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Vec<Message>, ParseError> {
+        if self.format.is_none() {
+            self.format = WireFormat::detect(bytes);
+        }
+        match self.format {
+            Some(WireFormat::Binary) => self.feed_binary(bytes),
+            // Legacy chunks carry no length, so each read still has to be one chunk
+            Some(WireFormat::Legacy) if !bytes.is_empty() => {
+                let msg = legacy::decode(bytes).and_then(|chunk| self.chunks.push(chunk));
+                self.ready.extend(msg.transpose());
+            }
+            Some(WireFormat::Legacy) | None if bytes.is_empty() => {}
+            _ => self.ready.push_back(Err(ParseError::BadMagic)),
+        }
+        self.next_batch()
+    }
+
+    fn feed_binary(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        loop {
+            match Frame::decode(&mut self.buf) {
+                // The framing is still intact after a rejected message, so the frames after it are fine
+                Ok(Some(frame)) => self
+                    .ready
+                    .extend(self.chunks.push(frame.into()).transpose()),
+                Ok(None) => return,
+                Err(err) => {
+                    // There is no way to find the next frame boundary after a bad header
+                    self.buf.clear();
+                    self.chunks = Chunks::default();
+                    self.ready.push_back(Err(err));
+                    return;
+                }
             }
         }
     }
+
+    fn next_batch(&mut self) -> Result<Vec<Message>, ParseError> {
+        if let Some(Err(_)) = self.ready.front() {
+            return Err(self.ready.pop_front().unwrap().unwrap_err());
+        }
+        let mut messages = vec![];
+        while let Some(Ok(_)) = self.ready.front() {
+            messages.extend(self.ready.pop_front().unwrap().ok());
+        }
+        Ok(messages)
+    }
+}
+
+// A chunked message being put back together.
+#[derive(Default)]
+struct Chunks {
+    pending: Option<Message>,
+    // Set once a message has grown past MAX_MESSAGE, until its last chunk has gone by.
+    discarding: bool,
+}
+
+impl Chunks {
+    // Adds `chunk` to the message being put together, and returns the message once its last
+    // chunk is in. A message that would grow past MAX_MESSAGE is rejected and the rest of it
+    // skipped.
+    fn push(&mut self, chunk: Message) -> Result<Option<Message>, ParseError> {
+        if self.discarding {
+            self.discarding = chunk.has_more;
+            return Ok(None);
+        }
+        let mut msg = self
+            .pending
+            .take()
+            .unwrap_or_else(|| Message::new(chunk.peer, vec![], false));
+        let size = msg.content.len() + chunk.content.len();
+        if size > MAX_MESSAGE {
+            self.discarding = chunk.has_more;
+            return Err(ParseError::MessageTooLarge(size));
+        }
+        msg.peer = chunk.peer;
+        msg.origin = chunk.origin;
+        msg.id = chunk.id;
+        msg.content.extend_from_slice(&chunk.content);
+        if chunk.has_more {
+            self.pending = Some(msg);
+            return Ok(None);
+        }
+        Ok(Some(msg))
+    }
+}
+
+// The binary framing for tokio's Framed: whole messages in, chunked frames out. Unlike
+// FrameReader it doesn't fall back to the legacy format.
+#[derive(Default)]
+pub struct MessageCodec {
+    chunks: Chunks,
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Self::Error> {
+        loop {
+            let frame = Frame::decode(src).map_err(|e| {
+                // As in FrameReader, nothing after a bad header can be trusted
+                src.clear();
+                self.chunks = Chunks::default();
+                std::io::Error::new(ErrorKind::InvalidData, format!("{:?}", e))
+            })?;
+            let Some(frame) = frame else {
+                return Ok(None);
+            };
+            let msg = self
+                .chunks
+                .push(frame.into())
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+            if let Some(msg) = msg {
+                return Ok(Some(msg));
+            }
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        for part in Message::encode_with_id(msg.peer, msg.origin, msg.id, &msg.content) {
+            dst.extend_from_slice(&part);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::PeerId;
+    use proptest::prelude::*;
+
+    fn stream_of(messages: &[(PeerId, Vec<u8>)]) -> Vec<u8> {
+        messages
+            .iter()
+            .flat_map(|(peer, content)| Message::encode(*peer, content))
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_coalesced_frames_in_one_read() {
+        let stream = stream_of(&[(1, b"first".to_vec()), (2, b"second".to_vec())]);
+        let mut reader = FrameReader::new();
+        let messages = reader.feed(&stream).unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                Message::new(1, b"first".to_vec(), false),
+                Message::new(2, b"second".to_vec(), false),
+            ]
+        );
+        assert_eq!(reader.format(), Some(WireFormat::Binary));
+        assert_eq!(reader.buf.len(), 0);
+    }
+
+    #[test]
+    fn test_split_frame_carries_over() {
+        let stream = stream_of(&[(3, b"payload ending in 1".to_vec())]);
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.feed(&stream[..HEADER_LEN + 2]).unwrap(), vec![]);
+        assert_eq!(reader.buf.len(), HEADER_LEN + 2);
+        let messages = reader.feed(&stream[HEADER_LEN + 2..]).unwrap();
+        assert_eq!(
+            messages,
+            vec![Message::new(3, b"payload ending in 1".to_vec(), false)]
+        );
+    }
+
+    #[test]
+    fn test_oversized_message_is_rejected() {
+        let stream = stream_of(&[
+            (4, b"first".to_vec()),
+            (5, vec![b'X'; MAX_MESSAGE + MAX_PAYLOAD]),
+            (6, b"next".to_vec()),
+        ]);
+        let mut reader = FrameReader::new();
+        assert_eq!(
+            reader.feed(&stream).unwrap(),
+            vec![Message::new(4, b"first".to_vec(), false)]
+        );
+        assert_eq!(
+            reader.feed(&[]),
+            Err(ParseError::MessageTooLarge(MAX_MESSAGE + MAX_PAYLOAD))
+        );
+        assert!(reader.chunks.pending.is_none());
+        // The rest of the oversized message is skipped
+        assert_eq!(
+            reader.feed(&[]).unwrap(),
+            vec![Message::new(6, b"next".to_vec(), false)]
+        );
+    }
+
+    #[test]
+    fn test_chunked_message_is_reassembled() {
+        let content = vec![b'Z'; MAX_PAYLOAD * 2 + 10];
+        let stream = stream_of(&[(4, content.clone())]);
+        let mut reader = FrameReader::new();
+        assert_eq!(
+            reader.feed(&stream).unwrap(),
+            vec![Message::new(4, content, false)]
+        );
+    }
+
+    #[test]
+    fn test_legacy_read() {
+        let mut reader = FrameReader::new();
+        let messages = reader.feed(b"0042Hello!0").unwrap();
+        assert_eq!(messages, vec![Message::new(42, b"Hello!".to_vec(), false)]);
+        assert_eq!(reader.format(), Some(WireFormat::Legacy));
+    }
+
+    #[test]
+    fn test_bad_header_resets_buffer() {
+        let mut reader = FrameReader::new();
+        let mut stream = stream_of(&[(1, b"ok".to_vec())]);
+        stream.extend_from_slice(&[0; HEADER_LEN]);
+        stream[HEADER_LEN + 2] = b'?'; // garbage where the next frame should start
+        assert_eq!(
+            reader.feed(&stream).unwrap(),
+            vec![Message::new(1, b"ok".to_vec(), false)]
+        );
+        assert_eq!(reader.feed(&[]), Err(ParseError::BadMagic));
+        assert_eq!(reader.buf.len(), 0);
+    }
+
+    // Hands out its bytes in one read, then would block like an idle non-blocking socket.
+    struct OneRead(Option<Vec<u8>>);
+
+    impl Read for OneRead {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.take() {
+                Some(bytes) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                None => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_from_keeps_messages_after_a_rejected_one() {
+        let mut frames = Message::encode(5, &vec![b'X'; MAX_MESSAGE + MAX_PAYLOAD]);
+        // Its last chunk, the one that makes it too large, arrives with the next messages
+        let last = frames.pop().unwrap();
+        let mut reader = FrameReader::new();
+        let mut head = stream_of(&[(4, b"first".to_vec())]);
+        head.extend(frames.concat());
+        assert_eq!(
+            reader.feed(&head).unwrap(),
+            vec![Message::new(4, b"first".to_vec(), false)]
+        );
+
+        let mut tail = last;
+        tail.extend(stream_of(&[(6, b"next".to_vec()), (7, b"after".to_vec())]));
+        let mut input = OneRead(Some(tail));
+        assert!(matches!(
+            reader.read_from(&mut input),
+            Err(ExtractError::InvalidMessage(ParseError::MessageTooLarge(_)))
+        ));
+        // Nothing more is coming from the socket, so these were already decoded
+        assert_eq!(
+            reader.read_from(&mut input).unwrap(),
+            vec![
+                Message::new(6, b"next".to_vec(), false),
+                Message::new(7, b"after".to_vec(), false),
+            ]
+        );
+        assert!(matches!(
+            reader.read_from(&mut input),
+            Err(ExtractError::NotReady)
+        ));
+    }
+
+    #[test]
+    fn test_read_from_stream() {
+        let stream = stream_of(&[(5, b"from a reader".to_vec())]);
+        let mut reader = FrameReader::new();
+        let mut input = stream.as_slice();
+        let messages = reader.read_from(&mut input).unwrap();
+        assert_eq!(
+            messages,
+            vec![Message::new(5, b"from a reader".to_vec(), false)]
+        );
+        assert!(matches!(
+            reader.read_from(&mut input),
+            Err(ExtractError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        let mut codec = MessageCodec::default();
+        let mut buf = BytesMut::new();
+        let mut long = Message::new(6, vec![b'L'; MAX_PAYLOAD + 5], false);
+        long.id = Some(77);
+        codec.encode(long.clone(), &mut buf).unwrap();
+        codec
+            .encode(Message::new(7, b"short".to_vec(), false), &mut buf)
+            .unwrap();
+
+        let mut partial = buf.split_to(HEADER_LEN + 3);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(long));
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some(Message::new(7, b"short".to_vec(), false))
+        );
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+
+        let mut garbage = BytesMut::from(&[b'?'; HEADER_LEN][..]);
+        assert!(codec.decode(&mut garbage).is_err());
+        assert!(garbage.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_any_split_yields_same_messages(
+            messages in prop::collection::vec(
+                (any::<PeerId>(), prop::collection::vec(any::<u8>(), 0..3000)),
+                1..8,
+            ),
+            splits in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+        ) {
+            let stream = stream_of(&messages);
+            let mut cuts = splits.iter().map(|i| i.index(stream.len() + 1)).collect::<Vec<_>>();
+            cuts.push(0);
+            cuts.push(stream.len());
+            cuts.sort_unstable();
+
+            let mut reader = FrameReader::new();
+            let mut received = vec![];
+            for window in cuts.windows(2) {
+                received.extend(reader.feed(&stream[window[0]..window[1]]).unwrap());
+            }
+
+            let expected = messages
+                .into_iter()
+                .map(|(peer, content)| Message::new(peer, content, false))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(received, expected);
+            prop_assert_eq!(reader.buf.len(), 0);
+        }
+    }
 }
@@ -0,0 +1,317 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, WebPkiSupportedAlgorithms, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+
+// PEM files the server presents to clients.
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub enum Trust {
+    // Accept any certificate for `server_name` issued by one of these CAs.
+    CaBundle(PathBuf),
+    // Accept exactly this certificate and nothing else, whatever name it carries.
+    Pinned(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    pub trust: Trust,
+    pub server_name: String,
+}
+
+// Either a bare socket or a TLS session over it; the framing code can't tell the difference.
+pub enum Stream<S: Read + Write> {
+    Plain(S),
+    Server(Box<StreamOwned<ServerConnection, S>>),
+    Client(Box<StreamOwned<ClientConnection, S>>),
+}
+
+impl<S: Read + Write> Stream<S> {
+    pub fn server(sock: S, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Stream::Server(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    pub fn client(sock: S, config: Arc<ClientConfig>, server_name: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+        Ok(Stream::Client(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    pub fn get_ref(&self) -> &S {
+        match self {
+            Stream::Plain(sock) => sock,
+            Stream::Server(tls) => tls.get_ref(),
+            Stream::Client(tls) => tls.get_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        match self {
+            Stream::Plain(sock) => sock,
+            Stream::Server(tls) => tls.get_mut(),
+            Stream::Client(tls) => tls.get_mut(),
+        }
+    }
+
+    // Encrypted records are waiting for the socket.
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Server(tls) => tls.conn.wants_write(),
+            Stream::Client(tls) => tls.conn.wants_write(),
+        }
+    }
+
+    // Queues a TLS close_notify so the peer sees a clean end of stream; it goes out on the next flush.
+    pub fn send_close_notify(&mut self) {
+        match self {
+            Stream::Plain(_) => {}
+            Stream::Server(tls) => tls.conn.send_close_notify(),
+            Stream::Client(tls) => tls.conn.send_close_notify(),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for Stream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.read(buf),
+            Stream::Server(tls) => tls.read(buf),
+            Stream::Client(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl<S: Read + Write> Write for Stream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.write(buf),
+            Stream::Server(tls) => tls.write(buf),
+            Stream::Client(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.flush(),
+            Stream::Server(tls) => tls.flush(),
+            Stream::Client(tls) => tls.flush(),
+        }
+    }
+}
+
+pub fn server_config(tls: &ServerTls) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert_path)
+        .map_err(io::Error::other)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io::Error::other)?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path).map_err(io::Error::other)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(tls: &ClientTls) -> io::Result<Arc<ClientConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let algorithms = provider.signature_verification_algorithms;
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let config = match &tls.trust {
+        Trust::CaBundle(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path).map_err(io::Error::other)? {
+                roots
+                    .add(cert.map_err(io::Error::other)?)
+                    .map_err(io::Error::other)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Trust::Pinned(path) => {
+            let cert = CertificateDer::from_pem_file(path).map_err(io::Error::other)?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCert { cert, algorithms }))
+                .with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+// Trusts one known certificate instead of a CA, e.g. a self-signed one on a private server.
+// The handshake signatures are still checked, so the server must hold the matching key.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.cert.as_ref() {
+            return Err(rustls::Error::General(
+                "Server certificate does not match the pinned one".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use std::{
+        fs,
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    pub(crate) struct TestCerts {
+        pub ca: PathBuf,
+        pub server: ServerTls,
+    }
+
+    // A throwaway CA and a "localhost" certificate signed by it, written to a fresh temp dir.
+    pub(crate) fn generate_certs() -> TestCerts {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "md-redis-tls-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+
+        let certs = TestCerts {
+            ca: dir.join("ca.pem"),
+            server: ServerTls {
+                cert_path: dir.join("cert.pem"),
+                key_path: dir.join("key.pem"),
+            },
+        };
+        fs::write(&certs.ca, ca.pem()).unwrap();
+        fs::write(&certs.server.cert_path, cert.pem()).unwrap();
+        fs::write(&certs.server.key_path, key.serialize_pem()).unwrap();
+        certs
+    }
+
+    // Runs one handshake and echoes a line back, returning what the client read.
+    fn echo(server: &ServerTls, client: &ClientTls) -> io::Result<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server_config(server).unwrap();
+        let echo = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut stream = Stream::server(sock, server).unwrap();
+            let mut buf = [0; 5];
+            if stream.read_exact(&mut buf).is_ok() {
+                let _ = stream.write_all(&buf);
+            }
+        });
+
+        let sock = TcpStream::connect(addr).unwrap();
+        let config = client_config(client).unwrap();
+        let mut stream = Stream::client(sock, config, &client.server_name).unwrap();
+        let result = stream.write_all(b"hello").and_then(|_| {
+            let mut buf = vec![0; 5];
+            stream.read_exact(&mut buf)?;
+            Ok(buf)
+        });
+        drop(stream);
+        echo.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_ca_bundle() {
+        let certs = generate_certs();
+        let client = ClientTls {
+            trust: Trust::CaBundle(certs.ca.clone()),
+            server_name: "localhost".to_string(),
+        };
+        assert_eq!(echo(&certs.server, &client).unwrap(), b"hello");
+
+        let wrong_name = ClientTls {
+            server_name: "example.com".to_string(),
+            ..client
+        };
+        assert!(echo(&certs.server, &wrong_name).is_err());
+    }
+
+    #[test]
+    fn test_pinned_cert() {
+        let certs = generate_certs();
+        let client = ClientTls {
+            trust: Trust::Pinned(certs.server.cert_path.clone()),
+            server_name: "anything".to_string(),
+        };
+        assert_eq!(echo(&certs.server, &client).unwrap(), b"hello");
+
+        // Any other certificate is refused
+        let other = generate_certs();
+        let client = ClientTls {
+            trust: Trust::Pinned(other.server.cert_path.clone()),
+            server_name: "localhost".to_string(),
+        };
+        assert!(echo(&certs.server, &client).is_err());
+    }
+}
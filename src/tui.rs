@@ -0,0 +1,226 @@
+use std::{io, time::Duration};
+
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers};
+use md_redis::{Client, ConnectionState};
+use ratatui::{
+    DefaultTerminal, Frame,
+    layout::{Constraint, Layout, Position},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+};
+
+use crate::chat::{ChatView, wrap};
+
+const SIDEBAR: u16 = 24;
+
+// How long to wait for a key before looking for events from the server again.
+const TICK: Duration = Duration::from_millis(50);
+
+// The chat window drawn in the terminal, for when there is no display: conversations on
+// the left, the open one on the right and a line to type in at the bottom. Enter sends, and
+// a line starting with '/' is a command; Up and Down (or Tab) switch conversations, Page
+// Up/Down scroll and Esc or Ctrl+C quits.
+pub fn run(client: Client) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = chat(&mut terminal, &client);
+    ratatui::restore();
+    client.close();
+    result
+}
+
+fn chat(terminal: &mut DefaultTerminal, client: &Client) -> io::Result<()> {
+    let fingerprint = client.fingerprint();
+    let mut view = ChatView::new();
+    loop {
+        while let Some(event) = client.try_recv() {
+            view.handle(&event);
+        }
+        let conversation = view.conversation(client);
+        let mut page = 0;
+        terminal.draw(|frame| page = draw(frame, &mut view, &conversation, &fingerprint))?;
+        view.mark_read(client);
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+        let TermEvent::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return Ok(()),
+            KeyCode::Char('c') if ctrl => return Ok(()),
+            KeyCode::Char(c) => view.input.insert(c),
+            KeyCode::Enter => view.submit(client),
+            KeyCode::Backspace => view.input.backspace(),
+            KeyCode::Delete => view.input.delete(),
+            KeyCode::Left => view.input.left(),
+            KeyCode::Right => view.input.right(),
+            KeyCode::Home => view.input.home(),
+            KeyCode::End => view.input.end(),
+            KeyCode::Up | KeyCode::BackTab => view.select_next(-1),
+            KeyCode::Down | KeyCode::Tab => view.select_next(1),
+            KeyCode::PageUp => view.scroll += page.max(1),
+            KeyCode::PageDown => view.scroll = view.scroll.saturating_sub(page.max(1)),
+            _ => {}
+        }
+    }
+}
+
+// Draws the whole screen and returns how many lines of conversation fit, for paging.
+fn draw(
+    frame: &mut Frame,
+    view: &mut ChatView,
+    conversation: &[String],
+    fingerprint: &str,
+) -> usize {
+    let [top, body, notice, input] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+        Constraint::Length(3),
+    ])
+    .areas(frame.area());
+    let [sidebar, pane] =
+        Layout::horizontal([Constraint::Length(SIDEBAR), Constraint::Min(0)]).areas(body);
+
+    let status = match view.connection {
+        ConnectionState::Connecting => "Connecting...".dark_gray(),
+        ConnectionState::Connected => "Connected".green(),
+        ConnectionState::RetryingIn(delay) => {
+            format!("Disconnected, retrying in {:.1}s", delay.as_secs_f32()).red()
+        }
+    };
+    let key = format!("  Your key: {}", fingerprint).dark_gray();
+    frame.render_widget(Line::from(vec![status, key]), top);
+
+    let entries = view.entries();
+    let items = entries.iter().map(|peer| {
+        let mut line = Line::from(view.name(*peer));
+        if !view.is_online(*peer) {
+            line.push_span(" (offline)");
+            line = line.dark_gray();
+        }
+        let unread = view.unread(*peer);
+        if unread > 0 {
+            line.push_span(format!(" ({})", unread).red().bold());
+        }
+        ListItem::new(line)
+    });
+    let selected = view
+        .selected
+        .and_then(|selected| entries.iter().position(|peer| *peer == selected));
+    let list = List::new(items)
+        .block(Block::bordered().title("Chats"))
+        .highlight_style(Style::new().reversed());
+    frame.render_stateful_widget(
+        list,
+        sidebar,
+        &mut ListState::default().with_selected(selected),
+    );
+
+    let block = match view.selected {
+        Some(peer) => Block::bordered().title(view.name(peer)),
+        None => Block::bordered(),
+    };
+    let inner = block.inner(pane);
+    frame.render_widget(block, pane);
+    let height = inner.height as usize;
+    if view.selected.is_none() {
+        let hint = "Pick someone to chat with using Up and Down".dark_gray();
+        frame.render_widget(hint, inner);
+    } else {
+        let lines = conversation
+            .iter()
+            .flat_map(|line| wrap(line, inner.width as i32, |text| text.chars().count() as i32))
+            .collect::<Vec<_>>();
+        let lines = lines[view.visible(lines.len(), height)]
+            .iter()
+            .map(|line| {
+                if line.starts_with("You: ") {
+                    Line::styled(line.as_str(), Style::new().blue())
+                } else {
+                    Line::from(line.as_str())
+                }
+            })
+            .collect::<Vec<_>>();
+        // Newest at the bottom, like the window
+        let [_, area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(lines.len() as u16)])
+                .areas(inner);
+        frame.render_widget(Paragraph::new(lines), area);
+    }
+
+    if let Some(text) = &view.notice {
+        frame.render_widget(Line::styled(text.as_str(), Style::new().red()), notice);
+    }
+
+    let block = Block::bordered();
+    let inner = block.inner(input);
+    frame.render_widget(block, input);
+    // Keep the cursor in view when the line is wider than the box
+    let cursor = view.input.before_cursor().chars().count() as u16;
+    let offset = cursor.saturating_sub(inner.width.saturating_sub(1));
+    frame.render_widget(Paragraph::new(view.input.text()).scroll((0, offset)), inner);
+    frame.set_cursor_position(Position::new(inner.x + cursor - offset, inner.y));
+    height
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md_redis::{
+        Event,
+        parser::{BROADCAST_PEER, Message, SystemMessage},
+    };
+    use ratatui::{Terminal, backend::TestBackend};
+
+    #[test]
+    fn test_draw() {
+        let mut view = ChatView::new();
+        view.handle(&Event::Connection(ConnectionState::Connected));
+        view.handle(&Event::System(SystemMessage::ServerInfo {
+            protocol: 1,
+            peer: 1,
+        }));
+        let peers = vec![
+            (1, "alice".to_string()),
+            (2, "bob".to_string()),
+            (3, "carol".to_string()),
+        ];
+        view.handle(&Event::Peers(peers));
+        let peers = vec![(1, "alice".to_string()), (2, "bob".to_string())];
+        view.handle(&Event::Peers(peers));
+        view.select(2);
+        let everyone = Message::new(BROADCAST_PEER, b"hey all".to_vec(), false);
+        view.handle(&Event::Message(everyone));
+        for c in "typing".chars() {
+            view.input.insert(c);
+        }
+
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        let conversation = ["bob: hi".to_string(), "You: hello (read)".to_string()];
+        terminal
+            .draw(|frame| {
+                assert_eq!(draw(frame, &mut view, &conversation, "abcd"), 5);
+            })
+            .unwrap();
+        let screen = terminal.backend().to_string();
+        for text in [
+            "Connected  Your key: abcd",
+            "Everyone (1)",
+            "alice (you)",
+            "carol (offline)",
+            "bob: hi",
+            "You: hello (read)",
+            "typing",
+        ] {
+            assert!(screen.contains(text), "{:?} not in\n{}", text, screen);
+        }
+        terminal.backend_mut().assert_cursor_position((7, 10));
+    }
+}